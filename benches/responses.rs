@@ -0,0 +1,74 @@
+//! Compares streaming the JSON encoding of a large response with building
+//! it as a string, as responses were sent before.
+//!
+//! Both write to `io::sink()`, so this measures encoding and copying only,
+//! not sending the response through hyper or compressing it.
+//!
+//! Run with `cargo bench --bench responses`.
+
+#[macro_use]
+extern crate bencher;
+extern crate rustc_serialize;
+extern crate webdriver;
+
+use std::io::{self, Write};
+
+use bencher::{black_box, Bencher};
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use rustc_serialize::json::{self, Json};
+use webdriver::response::{ValueResponse, WebDriverResponse};
+
+/// Size of the decoded screenshot, which is about 5.6 MB once base64
+/// encoded.
+const SCREENSHOT_SIZE: usize = 4 * 1024 * 1024;
+
+/// A response like that to a screenshot command.
+fn screenshot() -> WebDriverResponse {
+    // Pseudo-random bytes, so that the payload doesn't compress too well
+    let mut state = 0x2545_f491_u32;
+    let data = (0..SCREENSHOT_SIZE).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as u8
+    }).collect::<Vec<_>>();
+    WebDriverResponse::Generic(ValueResponse::new(Json::String(data.to_base64(STANDARD))))
+}
+
+fn response_len() -> u64 {
+    screenshot().to_json_string().len() as u64
+}
+
+/// The previous encoding: the value is encoded into one string, which is
+/// then copied into another for the `value` wrapper.
+fn encode_then_wrap(value: &Json) -> String {
+    let obj = json::encode(value).unwrap();
+    let mut data = String::with_capacity(11 + obj.len());
+    data.push_str("{\"value\": ");
+    data.push_str(&*obj);
+    data.push_str("}");
+    data
+}
+
+fn encode_buffered(b: &mut Bencher) {
+    let value = match screenshot() {
+        WebDriverResponse::Generic(x) => x.value,
+        _ => unreachable!()
+    };
+    b.bytes = response_len();
+    b.iter(|| {
+        let data = encode_then_wrap(&value);
+        io::sink().write_all(black_box(data.as_bytes())).unwrap();
+    })
+}
+
+fn encode_streamed(b: &mut Bencher) {
+    let response = screenshot();
+    b.bytes = response_len();
+    b.iter(|| {
+        response.write_json(black_box(io::sink())).unwrap();
+    })
+}
+
+benchmark_group!(benches, encode_buffered, encode_streamed);
+benchmark_main!(benches);
@@ -0,0 +1,104 @@
+//! Compares the segment trie router in `WebDriverHttpApi` with the linear
+//! scan over one regular expression per route that it replaced.
+//!
+//! Run with `cargo bench --bench routing`.
+
+#[macro_use]
+extern crate bencher;
+extern crate hyper;
+extern crate regex;
+extern crate webdriver;
+
+use bencher::{black_box, Bencher};
+use hyper::method::Method;
+use hyper::method::Method::{Delete, Get, Post};
+use regex::Regex;
+use webdriver::httpapi::{VoidWebDriverExtensionRoute, WebDriverHttpApi};
+
+/// A mix of requests, from routes near the start of the routing table to
+/// ones at the end and paths that don't match at all.
+static REQUESTS: &'static [(Method, &'static str)] = &[
+    (Post, "/session"),
+    (Post, "/session/e2b6c8a3-5f3c-4a5e-9a0e-3d2f1f0b7c41/url"),
+    (Get, "/session/e2b6c8a3-5f3c-4a5e-9a0e-3d2f1f0b7c41/title"),
+    (Post, "/session/e2b6c8a3-5f3c-4a5e-9a0e-3d2f1f0b7c41/element"),
+    (Post, "/session/e2b6c8a3-5f3c-4a5e-9a0e-3d2f1f0b7c41/element/8d4c5a2b-1f6e/click"),
+    (Get, "/session/e2b6c8a3-5f3c-4a5e-9a0e-3d2f1f0b7c41/element/8d4c5a2b-1f6e/attribute/href"),
+    (Get, "/session/e2b6c8a3-5f3c-4a5e-9a0e-3d2f1f0b7c41/cookie/session%20id"),
+    (Post, "/session/e2b6c8a3-5f3c-4a5e-9a0e-3d2f1f0b7c41/actions"),
+    (Delete, "/session/e2b6c8a3-5f3c-4a5e-9a0e-3d2f1f0b7c41/actions"),
+    (Get, "/status"),
+    (Get, "/session/e2b6c8a3-5f3c-4a5e-9a0e-3d2f1f0b7c41/unknown"),
+    (Post, "/session/e2b6c8a3-5f3c-4a5e-9a0e-3d2f1f0b7c41/title"),
+];
+
+fn api() -> WebDriverHttpApi<VoidWebDriverExtensionRoute> {
+    WebDriverHttpApi::new(&[])
+}
+
+/// The previous router: every route with the right method is tried in turn.
+struct RegexRouter {
+    routes: Vec<(Method, String, Regex)>,
+}
+
+impl RegexRouter {
+    fn new(api: &WebDriverHttpApi<VoidWebDriverExtensionRoute>) -> RegexRouter {
+        RegexRouter {
+            routes: api.routes()
+                .into_iter()
+                .map(|(method, path)| (method.clone(), path.to_string(), compile_path(path)))
+                .collect(),
+        }
+    }
+
+    fn lookup(&self, method: &Method, path: &str) -> Option<(&str, Vec<String>)> {
+        for &(ref route_method, ref template, ref regex) in self.routes.iter() {
+            if method == route_method {
+                if let Some(captures) = regex.captures(path) {
+                    let params = captures.iter()
+                        .skip(1)
+                        .filter_map(|x| x.map(|x| x.as_str().to_string()))
+                        .collect();
+                    return Some((&template[..], params))
+                }
+            }
+        }
+        None
+    }
+}
+
+fn compile_path(path: &str) -> Regex {
+    let mut rv = String::new();
+    rv.push_str("^");
+    for component in path.split('/') {
+        if component.starts_with("{") {
+            rv.push_str(&format!("(?P<{}>[^/]+)/", &component[1..component.len() - 1]));
+        } else {
+            rv.push_str(&format!("{}/", component));
+        }
+    }
+    rv.pop();
+    rv.push_str("$");
+    Regex::new(&rv).unwrap()
+}
+
+fn regex_router(b: &mut Bencher) {
+    let router = RegexRouter::new(&api());
+    b.iter(|| {
+        for &(ref method, path) in REQUESTS.iter() {
+            black_box(router.lookup(method, path));
+        }
+    })
+}
+
+fn trie_router(b: &mut Bencher) {
+    let api = api();
+    b.iter(|| {
+        for &(ref method, path) in REQUESTS.iter() {
+            black_box(api.match_route(method, path).ok().map(|x| (x.template, x.params)));
+        }
+    })
+}
+
+benchmark_group!(benches, regex_router, trie_router);
+benchmark_main!(benches);
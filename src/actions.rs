@@ -0,0 +1,599 @@
+//! Builder for the parameters of the Perform Actions command.
+//!
+//! Each action is added to an input source, selected with [`key`] or
+//! [`pointer`]. Actions normally happen one after another: every action gets
+//! its own tick, and the other sources pause during it. Actions added inside
+//! [`concurrently`] instead share ticks across sources, which is how
+//! multi-touch gestures are built.
+//!
+//! ```
+//! # use webdriver::actions::Actions;
+//! # use webdriver::command::PointerType;
+//! let actions = Actions::new()
+//!     .key("keyboard").down('\u{E009}').send_keys("a")
+//!     .pointer("mouse", PointerType::Mouse).move_to(10, 20).press(0).release(0)
+//!     .key("keyboard").up('\u{E009}')
+//!     .build()
+//!     .unwrap();
+//! assert_eq!(actions.actions.len(), 2);
+//! ```
+//!
+//! Using a source id for sources of different types is an error, returned
+//! from [`build`].
+//!
+//! [`key`]: struct.Actions.html#method.key
+//! [`pointer`]: struct.Actions.html#method.pointer
+//! [`concurrently`]: struct.Actions.html#method.concurrently
+//! [`build`]: struct.Actions.html#method.build
+
+use command::{ActionSequence, ActionsParameters, ActionsType, GeneralAction, KeyAction,
+              KeyActionItem, KeyDownAction, KeyUpAction, NullActionItem, PauseAction,
+              PointerAction, PointerActionItem, PointerActionParameters, PointerDownAction,
+              PointerMoveAction, PointerOrigin, PointerType, PointerUpAction};
+use common::{Nullable, WebElement};
+use error::{ErrorStatus, WebDriverError, WebDriverResult};
+
+const DEFAULT_KEY_SOURCE: &'static str = "keyboard";
+const DEFAULT_POINTER_SOURCE: &'static str = "mouse";
+const DEFAULT_NULL_SOURCE: &'static str = "none";
+
+/// Builds `ActionsParameters` one action at a time.
+#[derive(Clone, Default)]
+pub struct Actions {
+    sources: Vec<ActionSequence>,
+    current: Option<usize>,
+    /// Tick at which the enclosing `concurrently` block started, if any.
+    concurrent: Option<usize>,
+    /// The first source that was selected with the wrong type. Later
+    /// actions are ignored and `build` fails.
+    error: Option<String>,
+}
+
+impl Actions {
+    pub fn new() -> Actions {
+        Default::default()
+    }
+
+    /// Select the key source `id`, adding it if it doesn't exist yet.
+    ///
+    /// It is an error if `id` is already used by a source that isn't a key
+    /// source.
+    pub fn key(mut self, id: &str) -> Actions {
+        self.select(id, ActionsType::Key(vec![]));
+        self
+    }
+
+    /// Select the pointer source `id`, adding it with the given type if it
+    /// doesn't exist yet.
+    ///
+    /// It is an error if `id` is already used by a source that isn't a
+    /// pointer source of the same type.
+    pub fn pointer(mut self, id: &str, pointer_type: PointerType) -> Actions {
+        self.select(id, ActionsType::Pointer(PointerActionParameters {
+            pointer_type: pointer_type
+        }, vec![]));
+        self
+    }
+
+    /// Pause the selected source for `duration` milliseconds, or a source
+    /// without actions if none is selected.
+    pub fn pause(mut self, duration: u64) -> Actions {
+        if self.current.is_none() {
+            self.select(DEFAULT_NULL_SOURCE, ActionsType::Null(vec![]));
+        }
+        let index = match self.current {
+            Some(index) => index,
+            None => return self
+        };
+        let pause = GeneralAction::Pause(PauseAction { duration: duration });
+        self.push(index, |actions| match *actions {
+            ActionsType::Null(ref mut items) => items.push(NullActionItem::General(pause)),
+            ActionsType::Key(ref mut items) => items.push(KeyActionItem::General(pause)),
+            ActionsType::Pointer(_, ref mut items) => {
+                items.push(PointerActionItem::General(pause))
+            }
+        });
+        self
+    }
+
+    /// Press `key` on the selected key source.
+    ///
+    /// If the selected source isn't a key source, the `keyboard` source is
+    /// selected first. The same applies to `up` and `send_keys`.
+    pub fn down(self, key: char) -> Actions {
+        self.key_action(KeyAction::Down(KeyDownAction { value: key }))
+    }
+
+    /// Release `key` on the selected key source.
+    pub fn up(self, key: char) -> Actions {
+        self.key_action(KeyAction::Up(KeyUpAction { value: key }))
+    }
+
+    /// Press and release each character of `text` in turn.
+    pub fn send_keys(self, text: &str) -> Actions {
+        text.chars().fold(self, |actions, key| actions.down(key).up(key))
+    }
+
+    /// Press `button` on the selected pointer source.
+    ///
+    /// If the selected source isn't a pointer source, the `mouse` source is
+    /// selected first. The same applies to the other pointer actions.
+    pub fn press(self, button: u64) -> Actions {
+        self.pointer_action(PointerAction::Down(PointerDownAction { button: button }))
+    }
+
+    /// Release `button` on the selected pointer source.
+    pub fn release(self, button: u64) -> Actions {
+        self.pointer_action(PointerAction::Up(PointerUpAction { button: button }))
+    }
+
+    /// Move the selected pointer to a position in the viewport.
+    pub fn move_to(self, x: i64, y: i64) -> Actions {
+        self.move_with(PointerOrigin::Viewport, x, y, None)
+    }
+
+    /// Move the selected pointer relative to its current position.
+    pub fn move_by(self, x: i64, y: i64) -> Actions {
+        self.move_with(PointerOrigin::Pointer, x, y, None)
+    }
+
+    /// Move the selected pointer to the centre of `element`.
+    pub fn move_to_element(self, element: &WebElement) -> Actions {
+        self.move_with(PointerOrigin::Element(element.clone()), 0, 0, None)
+    }
+
+    /// Move the selected pointer to an offset from `origin`, taking
+    /// `duration` milliseconds if given.
+    pub fn move_with<T: Into<Option<u64>>>(self, origin: PointerOrigin, x: i64, y: i64,
+                                           duration: T) -> Actions {
+        self.pointer_action(PointerAction::Move(PointerMoveAction {
+            duration: duration.into().into(),
+            origin: origin,
+            x: Nullable::Value(x),
+            y: Nullable::Value(y),
+        }))
+    }
+
+    /// Cancel the selected pointer.
+    pub fn cancel(self) -> Actions {
+        self.pointer_action(PointerAction::Cancel)
+    }
+
+    /// Add the actions built by `f` so that they happen at the same time
+    /// across sources.
+    ///
+    /// Within `f`, each source's actions occupy consecutive ticks starting
+    /// from the same tick, rather than one tick per action overall.
+    /// Afterwards the sources are padded with pauses so that later actions
+    /// start once all of them have finished.
+    pub fn concurrently<F: FnOnce(Actions) -> Actions>(mut self, f: F) -> Actions {
+        let concurrent = self.concurrent;
+        self.align();
+        self.concurrent = Some(self.ticks());
+        let mut actions = f(self);
+        actions.align();
+        actions.concurrent = concurrent;
+        actions
+    }
+
+    /// Click `element` twice with the left mouse button.
+    ///
+    /// This uses the selected pointer if it is a mouse, and the `mouse`
+    /// source otherwise. The same applies to `drag_and_drop`.
+    pub fn double_click(self, element: &WebElement) -> Actions {
+        self.select_mouse()
+            .move_to_element(element)
+            .press(0).release(0)
+            .press(0).release(0)
+    }
+
+    /// Drag `source` onto `target` with the left mouse button.
+    pub fn drag_and_drop(self, source: &WebElement, target: &WebElement) -> Actions {
+        self.select_mouse()
+            .move_to_element(source)
+            .press(0)
+            .move_to_element(target)
+            .release(0)
+    }
+
+    /// Swipe a finger from `from` to `to` in the viewport over `duration`
+    /// milliseconds.
+    ///
+    /// The finger is a new touch source named `finger1`, or `finger2` and so
+    /// on if that id is taken. The same applies to the fingers of `pinch`.
+    pub fn swipe(self, from: (i64, i64), to: (i64, i64), duration: u64) -> Actions {
+        let finger = self.unused_ids("finger", 1).remove(0);
+        self.pointer(&finger, PointerType::Touch)
+            .move_to(from.0, from.1)
+            .press(0)
+            .move_with(PointerOrigin::Viewport, to.0, to.1, duration)
+            .release(0)
+    }
+
+    /// Move two fingers horizontally around `centre`, from `start` apart to
+    /// `end` apart, over `duration` milliseconds.
+    ///
+    /// This pinches when `end` is less than `start` and zooms otherwise.
+    pub fn pinch(self, centre: (i64, i64), start: i64, end: i64, duration: u64) -> Actions {
+        let (x, y) = centre;
+        let fingers = self.unused_ids("finger", 2);
+        self.concurrently(|actions| {
+            actions
+                .pointer(&fingers[0], PointerType::Touch)
+                .move_to(x - start / 2, y).press(0)
+                .move_with(PointerOrigin::Viewport, x - end / 2, y, duration).release(0)
+                .pointer(&fingers[1], PointerType::Touch)
+                .move_to(x + start / 2, y).press(0)
+                .move_with(PointerOrigin::Viewport, x + end / 2, y, duration).release(0)
+        })
+    }
+
+    /// Finish building, padding every source to the same number of ticks.
+    ///
+    /// Fails with `InvalidArgument` if a source id was used for sources of
+    /// different types.
+    pub fn build(mut self) -> WebDriverResult<ActionsParameters> {
+        if let Some(message) = self.error {
+            return Err(WebDriverError::new(ErrorStatus::InvalidArgument, message));
+        }
+        self.align();
+        Ok(ActionsParameters {
+            actions: self.sources
+        })
+    }
+
+    /// Select the source `id`, adding it with `actions` if it doesn't exist
+    /// yet. An existing source must be of the same type as `actions`;
+    /// otherwise the error is recorded and no source is selected.
+    fn select(&mut self, id: &str, actions: ActionsType) {
+        if self.error.is_some() {
+            return;
+        }
+        let index = self.sources.iter().position(|source| match source.id {
+            Nullable::Value(ref x) => x == id,
+            Nullable::Null => false
+        });
+        let index = match index {
+            Some(index) => {
+                let existing = &self.sources[index].actions;
+                if source_type(existing) != source_type(&actions) {
+                    self.error = Some(format!("Input source {} is a {} source, not a {} source",
+                                              id, source_type(existing), source_type(&actions)));
+                    self.current = None;
+                    return;
+                }
+                index
+            },
+            None => {
+                let mut source = ActionSequence {
+                    id: Nullable::Value(id.to_string()),
+                    actions: actions
+                };
+                let ticks = self.concurrent.unwrap_or_else(|| self.ticks());
+                pad(&mut source.actions, ticks);
+                self.sources.push(source);
+                self.sources.len() - 1
+            }
+        };
+        self.current = Some(index);
+    }
+
+    fn select_pointer(self) -> Actions {
+        match self.current_actions() {
+            Some(&ActionsType::Pointer(..)) => self,
+            _ => self.pointer(DEFAULT_POINTER_SOURCE, PointerType::Mouse)
+        }
+    }
+
+    fn select_mouse(self) -> Actions {
+        match self.current_actions() {
+            Some(&ActionsType::Pointer(PointerActionParameters {
+                pointer_type: PointerType::Mouse
+            }, _)) => self,
+            _ => self.pointer(DEFAULT_POINTER_SOURCE, PointerType::Mouse)
+        }
+    }
+
+    /// The first `count` ids made of `prefix` and a number from 1 that no
+    /// source uses yet.
+    fn unused_ids(&self, prefix: &str, count: usize) -> Vec<String> {
+        let used = |id: &str| self.sources.iter().any(|source| match source.id {
+            Nullable::Value(ref x) => x == id,
+            Nullable::Null => false
+        });
+        (1..)
+            .map(|n| format!("{}{}", prefix, n))
+            .filter(|id| !used(id))
+            .take(count)
+            .collect()
+    }
+
+    fn current_actions(&self) -> Option<&ActionsType> {
+        self.current.map(|index| &self.sources[index].actions)
+    }
+
+    fn select_key(self) -> Actions {
+        match self.current_actions() {
+            Some(&ActionsType::Key(_)) => self,
+            _ => self.key(DEFAULT_KEY_SOURCE)
+        }
+    }
+
+    fn key_action(mut self, action: KeyAction) -> Actions {
+        self = self.select_key();
+        let index = match self.current {
+            Some(index) => index,
+            None => return self
+        };
+        self.push(index, |actions| if let ActionsType::Key(ref mut items) = *actions {
+            items.push(KeyActionItem::Key(action))
+        });
+        self
+    }
+
+    fn pointer_action(mut self, action: PointerAction) -> Actions {
+        self = self.select_pointer();
+        let index = match self.current {
+            Some(index) => index,
+            None => return self
+        };
+        self.push(index, |actions| if let ActionsType::Pointer(_, ref mut items) = *actions {
+            items.push(PointerActionItem::Pointer(action))
+        });
+        self
+    }
+
+    /// Add an action to the source at `index`, in a tick of its own unless
+    /// building concurrently.
+    fn push<F: FnOnce(&mut ActionsType)>(&mut self, index: usize, f: F) {
+        if self.concurrent.is_none() {
+            self.align();
+        }
+        f(&mut self.sources[index].actions);
+    }
+
+    fn ticks(&self) -> usize {
+        self.sources.iter().map(|source| len(&source.actions)).max().unwrap_or(0)
+    }
+
+    fn align(&mut self) {
+        let ticks = self.ticks();
+        for source in self.sources.iter_mut() {
+            pad(&mut source.actions, ticks);
+        }
+    }
+}
+
+/// Name of the type of source that `actions` belong to.
+fn source_type(actions: &ActionsType) -> &'static str {
+    match *actions {
+        ActionsType::Null(_) => "null",
+        ActionsType::Key(_) => "key",
+        ActionsType::Pointer(ref parameters, _) => match parameters.pointer_type {
+            PointerType::Mouse => "mouse",
+            PointerType::Pen => "pen",
+            PointerType::Touch => "touch",
+        }
+    }
+}
+
+fn len(actions: &ActionsType) -> usize {
+    match *actions {
+        ActionsType::Null(ref items) => items.len(),
+        ActionsType::Key(ref items) => items.len(),
+        ActionsType::Pointer(_, ref items) => items.len(),
+    }
+}
+
+/// Add zero-length pauses until `actions` has `ticks` actions.
+fn pad(actions: &mut ActionsType, ticks: usize) {
+    let pause = || GeneralAction::Pause(PauseAction { duration: 0 });
+    match *actions {
+        ActionsType::Null(ref mut items) => {
+            while items.len() < ticks {
+                items.push(NullActionItem::General(pause()));
+            }
+        },
+        ActionsType::Key(ref mut items) => {
+            while items.len() < ticks {
+                items.push(KeyActionItem::General(pause()));
+            }
+        },
+        ActionsType::Pointer(_, ref mut items) => {
+            while items.len() < ticks {
+                items.push(PointerActionItem::General(pause()));
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json::{Json, ToJson};
+
+    use command::{ActionsParameters, Parameters, PointerType};
+    use common::WebElement;
+    use error::ErrorStatus;
+    use super::Actions;
+
+    fn check(actions: ActionsParameters, expected: &str) {
+        let expected = Json::from_str(expected).unwrap();
+        assert_eq!(actions.to_json().to_string(), expected.to_string());
+        assert!(ActionsParameters::from_json(&expected).unwrap() == actions);
+    }
+
+    #[test]
+    fn test_sequential() {
+        let actions = Actions::new()
+            .key("keyboard").down('a')
+            .pointer("mouse", PointerType::Mouse).move_to(1, 2).pause(10)
+            .key("keyboard").up('a')
+            .build().unwrap();
+        check(actions, r#"{"actions": [
+            {"id": "keyboard", "type": "key", "actions": [
+                {"type": "keyDown", "value": "a"},
+                {"type": "pause", "duration": 0},
+                {"type": "pause", "duration": 0},
+                {"type": "keyUp", "value": "a"}]},
+            {"id": "mouse", "type": "pointer", "parameters": {"pointerType": "mouse"},
+             "actions": [
+                {"type": "pause", "duration": 0},
+                {"type": "pointerMove", "origin": "viewport", "x": 1, "y": 2},
+                {"type": "pause", "duration": 10},
+                {"type": "pause", "duration": 0}]}]}"#);
+    }
+
+    #[test]
+    fn test_default_sources() {
+        let actions = Actions::new().pause(5).send_keys("ab").press(1).build().unwrap();
+        check(actions, r#"{"actions": [
+            {"id": "none", "type": "none", "actions": [
+                {"type": "pause", "duration": 5},
+                {"type": "pause", "duration": 0},
+                {"type": "pause", "duration": 0},
+                {"type": "pause", "duration": 0},
+                {"type": "pause", "duration": 0},
+                {"type": "pause", "duration": 0}]},
+            {"id": "keyboard", "type": "key", "actions": [
+                {"type": "pause", "duration": 0},
+                {"type": "keyDown", "value": "a"},
+                {"type": "keyUp", "value": "a"},
+                {"type": "keyDown", "value": "b"},
+                {"type": "keyUp", "value": "b"},
+                {"type": "pause", "duration": 0}]},
+            {"id": "mouse", "type": "pointer", "parameters": {"pointerType": "mouse"},
+             "actions": [
+                {"type": "pause", "duration": 0},
+                {"type": "pause", "duration": 0},
+                {"type": "pause", "duration": 0},
+                {"type": "pause", "duration": 0},
+                {"type": "pause", "duration": 0},
+                {"type": "pointerDown", "button": 1}]}]}"#);
+    }
+
+    #[test]
+    fn test_concurrently() {
+        let actions = Actions::new()
+            .key("keyboard").down('a')
+            .concurrently(|actions| actions.up('a').press(0).release(0))
+            .move_by(3, 4)
+            .build().unwrap();
+        check(actions, r#"{"actions": [
+            {"id": "keyboard", "type": "key", "actions": [
+                {"type": "keyDown", "value": "a"},
+                {"type": "keyUp", "value": "a"},
+                {"type": "pause", "duration": 0},
+                {"type": "pause", "duration": 0}]},
+            {"id": "mouse", "type": "pointer", "parameters": {"pointerType": "mouse"},
+             "actions": [
+                {"type": "pause", "duration": 0},
+                {"type": "pointerDown", "button": 0},
+                {"type": "pointerUp", "button": 0},
+                {"type": "pointerMove", "origin": "pointer", "x": 3, "y": 4}]}]}"#);
+    }
+
+    #[test]
+    fn test_gestures() {
+        let source = WebElement::new("a".to_string());
+        let target = WebElement::new("b".to_string());
+        let actions = Actions::new()
+            .drag_and_drop(&source, &target)
+            .double_click(&target)
+            .build().unwrap();
+        check(actions, r#"{"actions": [
+            {"id": "mouse", "type": "pointer", "parameters": {"pointerType": "mouse"},
+             "actions": [
+                {"type": "pointerMove", "x": 0, "y": 0,
+                 "origin": {"element-6066-11e4-a52e-4f735466cecf": "a"}},
+                {"type": "pointerDown", "button": 0},
+                {"type": "pointerMove", "x": 0, "y": 0,
+                 "origin": {"element-6066-11e4-a52e-4f735466cecf": "b"}},
+                {"type": "pointerUp", "button": 0},
+                {"type": "pointerMove", "x": 0, "y": 0,
+                 "origin": {"element-6066-11e4-a52e-4f735466cecf": "b"}},
+                {"type": "pointerDown", "button": 0},
+                {"type": "pointerUp", "button": 0},
+                {"type": "pointerDown", "button": 0},
+                {"type": "pointerUp", "button": 0}]}]}"#);
+
+        let actions = Actions::new().pinch((100, 50), 60, 20, 250).build().unwrap();
+        check(actions, r#"{"actions": [
+            {"id": "finger1", "type": "pointer", "parameters": {"pointerType": "touch"},
+             "actions": [
+                {"type": "pointerMove", "origin": "viewport", "x": 70, "y": 50},
+                {"type": "pointerDown", "button": 0},
+                {"type": "pointerMove", "origin": "viewport", "x": 90, "y": 50,
+                 "duration": 250},
+                {"type": "pointerUp", "button": 0}]},
+            {"id": "finger2", "type": "pointer", "parameters": {"pointerType": "touch"},
+             "actions": [
+                {"type": "pointerMove", "origin": "viewport", "x": 130, "y": 50},
+                {"type": "pointerDown", "button": 0},
+                {"type": "pointerMove", "origin": "viewport", "x": 110, "y": 50,
+                 "duration": 250},
+                {"type": "pointerUp", "button": 0}]}]}"#);
+
+        let actions = Actions::new().swipe((0, 100), (0, 10), 100).build().unwrap();
+        check(actions, r#"{"actions": [
+            {"id": "finger1", "type": "pointer", "parameters": {"pointerType": "touch"},
+             "actions": [
+                {"type": "pointerMove", "origin": "viewport", "x": 0, "y": 100},
+                {"type": "pointerDown", "button": 0},
+                {"type": "pointerMove", "origin": "viewport", "x": 0, "y": 10,
+                 "duration": 100},
+                {"type": "pointerUp", "button": 0}]}]}"#);
+    }
+
+    #[test]
+    fn test_fingers() {
+        let actions = Actions::new()
+            .key("finger1").down('a')
+            .swipe((0, 100), (0, 10), 100)
+            .pinch((100, 50), 60, 20, 250)
+            .build()
+            .unwrap();
+        let ids = actions.actions.iter()
+            .map(|source| source.id.to_json().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![r#""finger1""#, r#""finger2""#, r#""finger3""#, r#""finger4""#]);
+    }
+
+    #[test]
+    fn test_gestures_use_mouse() {
+        let target = WebElement::new("b".to_string());
+        let actions = Actions::new()
+            .pointer("pen", PointerType::Pen).press(0)
+            .double_click(&target)
+            .build()
+            .unwrap();
+        assert_eq!(actions.actions.len(), 2);
+        assert_eq!(actions.actions[1].id.to_json().to_string(), r#""mouse""#);
+    }
+
+    #[test]
+    fn test_pointer_type_mismatch() {
+        let err = Actions::new().pointer("mouse", PointerType::Mouse).press(0)
+            .pointer("mouse", PointerType::Pen).press(0)
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+        assert_eq!(err.message, "Input source mouse is a mouse source, not a pen source");
+    }
+
+    #[test]
+    fn test_source_type_mismatch() {
+        let err = Actions::new().pointer("mouse", PointerType::Mouse).key("mouse").down('a')
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.message, "Input source mouse is a mouse source, not a key source");
+
+        // Later sources of the right type don't undo the error
+        let err = Actions::new().key("mouse").down('a').press(0).key("keyboard").up('a')
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.message, "Input source mouse is a key source, not a mouse source");
+    }
+}
@@ -63,7 +63,7 @@ pub trait CapabilitiesMatching {
                                              -> WebDriverResult<Option<Capabilities>>;
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct SpecNewSessionParameters {
     pub alwaysMatch: Capabilities,
     pub firstMatch: Vec<Capabilities>,
@@ -454,7 +454,7 @@ impl CapabilitiesMatching for SpecNewSessionParameters {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct LegacyNewSessionParameters {
     pub desired: Capabilities,
     pub required: Capabilities,
@@ -0,0 +1,399 @@
+//! Blocking client for talking to a remote end over HTTP.
+//!
+//! Commands are encoded with the same route table that the server uses to
+//! decode them, see [`WebDriverHttpApi::encode_request`].
+//!
+//! ```no_run
+//! # use webdriver::client::Client;
+//! # use webdriver::common::LocatorStrategy;
+//! # use webdriver::error::WebDriverResult;
+//! # fn run() -> WebDriverResult<()> {
+//! let mut client = Client::new("http://localhost:4444")?;
+//! client.new_session(Client::default_session_parameters())?;
+//! client.goto("https://example.org/")?;
+//! let link = client.find(LocatorStrategy::CSSSelector, "a")?;
+//! client.click(&link)?;
+//! client.delete_session()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`WebDriverHttpApi::encode_request`]:
+//! ../httpapi/struct.WebDriverHttpApi.html#method.encode_request
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::time::Duration;
+
+use hyper;
+use hyper::header::ContentType;
+use hyper::method::Method;
+use rustc_serialize::json::Json;
+use url::Url;
+
+use capabilities::SpecNewSessionParameters;
+use command::{GetParameters, JavascriptCommandParameters, LocatorParameters,
+              NewSessionParameters, SendKeysParameters, WebDriverCommand, WebDriverMessage};
+use common::{LocatorStrategy, Nullable, WebElement};
+use error::{ErrorStatus, WebDriverError, WebDriverResult};
+use httpapi::{VoidWebDriverExtensionRoute, WebDriverExtensionRoute, WebDriverHttpApi};
+use response::{NewSessionResponse, WebDriverResponse};
+
+/// Client for a WebDriver remote end, such as a driver or a grid.
+///
+/// Commands are sent to the session created with [`new_session`], which
+/// is not deleted when the client is dropped.
+///
+/// [`new_session`]: #method.new_session
+pub struct Client<U: WebDriverExtensionRoute=VoidWebDriverExtensionRoute> {
+    http: hyper::Client,
+    /// Scheme, host and port of the remote end.
+    origin: String,
+    api: WebDriverHttpApi<U>,
+    session_id: Option<String>,
+}
+
+impl Client<VoidWebDriverExtensionRoute> {
+    /// Connect to the remote end at `url`, e.g. `http://localhost:4444` or
+    /// `http://grid:4444/wd/hub`.
+    pub fn new(url: &str) -> WebDriverResult<Client> {
+        Client::with_extension_routes(url, &[])
+    }
+
+    /// Parameters for a new session without any required capabilities.
+    pub fn default_session_parameters() -> NewSessionParameters {
+        NewSessionParameters::Spec(SpecNewSessionParameters {
+            alwaysMatch: BTreeMap::new(),
+            firstMatch: vec![],
+        })
+    }
+}
+
+impl<U: WebDriverExtensionRoute> Client<U> {
+    /// Connect to the remote end at `url`, which supports
+    /// `extension_routes` in addition to the standard commands.
+    pub fn with_extension_routes(url: &str, extension_routes: &[(Method, &str, U)])
+                                 -> WebDriverResult<Client<U>> {
+        let url = Url::parse(url).map_err(|e| {
+            WebDriverError::new(ErrorStatus::InvalidArgument, format!("Invalid URL {}: {}", url, e))
+        })?;
+        if url.scheme() != "http" {
+            return Err(WebDriverError::new(ErrorStatus::InvalidArgument,
+                                           format!("Unsupported URL scheme {}", url.scheme())));
+        }
+        let origin = url.origin().ascii_serialization();
+        let api = WebDriverHttpApi::try_new(extension_routes)?.with_prefix(url.path());
+        Ok(Client {
+            http: hyper::Client::new(),
+            origin: origin,
+            api: api,
+            session_id: None,
+        })
+    }
+
+    /// Time out requests that get no response within `timeout`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.http.set_read_timeout(timeout);
+        self.http.set_write_timeout(timeout);
+    }
+
+    /// The id of the current session, if one has been created.
+    pub fn session_id(&self) -> Option<&str> {
+        self.session_id.as_ref().map(|x| &x[..])
+    }
+
+    /// Create a session, which subsequent commands are sent to.
+    pub fn new_session(&mut self, parameters: NewSessionParameters)
+                       -> WebDriverResult<NewSessionResponse> {
+        if self.session_id.is_some() {
+            return Err(WebDriverError::new(ErrorStatus::SessionNotCreated,
+                                           "Client already has a session"));
+        }
+        match self.send(WebDriverCommand::NewSession(parameters))? {
+            WebDriverResponse::NewSession(response) => {
+                self.session_id = Some(response.sessionId.clone());
+                Ok(response)
+            },
+            _ => Err(unexpected_response())
+        }
+    }
+
+    /// Delete the current session.
+    pub fn delete_session(&mut self) -> WebDriverResult<()> {
+        self.send(WebDriverCommand::DeleteSession)?;
+        self.session_id = None;
+        Ok(())
+    }
+
+    /// Send `command` to the current session, or without a session for
+    /// commands such as `Status`.
+    ///
+    /// Error responses are returned as the `WebDriverError` they describe.
+    pub fn send(&self, command: WebDriverCommand<U::Command>) -> WebDriverResult<WebDriverResponse> {
+        let message = WebDriverMessage::<U>::new(self.session_id.clone(), command);
+        let (method, path, body) = self.api.encode_request(&message)?;
+        let url = format!("{}{}", self.origin, path);
+        debug!("Sending {} {}", method, url);
+
+        let mut request = self.http.request(method, &url[..]);
+        if !body.is_empty() {
+            request = request.header(ContentType::json()).body(&body[..]);
+        }
+        let mut response = request.send().map_err(|e| {
+            WebDriverError::new(ErrorStatus::UnknownError,
+                                format!("Failed to send request to {}: {}", url, e))
+        })?;
+        let mut data = String::new();
+        response.read_to_string(&mut data)?;
+        let json = Json::from_str(&data).map_err(|_| {
+            WebDriverError::new(ErrorStatus::UnknownError,
+                                format!("Invalid response with status {}: {}", response.status, data))
+        })?;
+        WebDriverResponse::from_json(&message.command, response.status, &json)
+    }
+
+    /// Send `command` and return the value of the response, which is `null`
+    /// for commands that don't return anything.
+    pub fn send_value(&self, command: WebDriverCommand<U::Command>) -> WebDriverResult<Json> {
+        match self.send(command)? {
+            WebDriverResponse::Generic(response) => Ok(response.value),
+            WebDriverResponse::Void => Ok(Json::Null),
+            _ => Err(unexpected_response())
+        }
+    }
+
+    /// Navigate to `url`.
+    pub fn goto(&self, url: &str) -> WebDriverResult<()> {
+        self.send(WebDriverCommand::Get(GetParameters { url: url.to_string() }))?;
+        Ok(())
+    }
+
+    pub fn current_url(&self) -> WebDriverResult<String> {
+        string_value(self.send_value(WebDriverCommand::GetCurrentUrl)?)
+    }
+
+    pub fn title(&self) -> WebDriverResult<String> {
+        string_value(self.send_value(WebDriverCommand::GetTitle)?)
+    }
+
+    pub fn window_handles(&self) -> WebDriverResult<Vec<String>> {
+        match self.send_value(WebDriverCommand::GetWindowHandles)? {
+            Json::Array(handles) => handles.into_iter().map(string_value).collect(),
+            _ => Err(unexpected_response())
+        }
+    }
+
+    /// Find the first element matching the selector `value`.
+    pub fn find(&self, using: LocatorStrategy, value: &str) -> WebDriverResult<WebElement> {
+        let parameters = LocatorParameters { using: using, value: value.to_string() };
+        element_value(&self.send_value(WebDriverCommand::FindElement(parameters))?)
+    }
+
+    /// Find all elements matching the selector `value`.
+    pub fn find_all(&self, using: LocatorStrategy, value: &str) -> WebDriverResult<Vec<WebElement>> {
+        let parameters = LocatorParameters { using: using, value: value.to_string() };
+        match self.send_value(WebDriverCommand::FindElements(parameters))? {
+            Json::Array(elements) => elements.iter().map(element_value).collect(),
+            _ => Err(unexpected_response())
+        }
+    }
+
+    pub fn click(&self, element: &WebElement) -> WebDriverResult<()> {
+        self.send(WebDriverCommand::ElementClick(element.clone()))?;
+        Ok(())
+    }
+
+    /// Type `text` into `element`.
+    pub fn send_keys(&self, element: &WebElement, text: &str) -> WebDriverResult<()> {
+        let parameters = SendKeysParameters { text: text.to_string() };
+        self.send(WebDriverCommand::ElementSendKeys(element.clone(), parameters))?;
+        Ok(())
+    }
+
+    pub fn text(&self, element: &WebElement) -> WebDriverResult<String> {
+        string_value(self.send_value(WebDriverCommand::GetElementText(element.clone()))?)
+    }
+
+    pub fn is_displayed(&self, element: &WebElement) -> WebDriverResult<bool> {
+        match self.send_value(WebDriverCommand::IsDisplayed(element.clone()))? {
+            Json::Boolean(displayed) => Ok(displayed),
+            _ => Err(unexpected_response())
+        }
+    }
+
+    /// Run `script` as the body of a function called with `args`, and
+    /// return its result.
+    pub fn execute(&self, script: &str, args: Vec<Json>) -> WebDriverResult<Json> {
+        let parameters = JavascriptCommandParameters {
+            script: script.to_string(),
+            args: Nullable::Value(args),
+        };
+        self.send_value(WebDriverCommand::ExecuteScript(parameters))
+    }
+}
+
+fn unexpected_response() -> WebDriverError {
+    WebDriverError::new(ErrorStatus::UnknownError, "Unexpected response from remote end")
+}
+
+fn string_value(value: Json) -> WebDriverResult<String> {
+    match value {
+        Json::String(x) => Ok(x),
+        _ => Err(unexpected_response())
+    }
+}
+
+fn element_value(value: &Json) -> WebDriverResult<WebElement> {
+    WebElement::from_json(value).map_err(|_| unexpected_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use rustc_serialize::json::{Json, ToJson};
+
+    use command::{WebDriverCommand, WebDriverMessage};
+    use common::{LocatorStrategy, WebElement};
+    use error::{ErrorStatus, WebDriverError, WebDriverResult};
+    use httpapi::VoidWebDriverExtensionRoute;
+    use response::{NewSessionResponse, ValueResponse, WebDriverResponse};
+    use server::{ServerBuilder, ServerHandle, Session, WebDriverHandler};
+    use super::Client;
+
+    /// A single page with a text input, whose value is shown in its title.
+    #[derive(Default)]
+    struct Page {
+        url: String,
+        text: String,
+        clicks: usize,
+    }
+
+    struct StubHandler {
+        page: Arc<Mutex<Page>>,
+    }
+
+    fn value<T: ToJson>(value: T) -> WebDriverResult<WebDriverResponse> {
+        Ok(WebDriverResponse::Generic(ValueResponse::new(value.to_json())))
+    }
+
+    impl WebDriverHandler for StubHandler {
+        fn handle_command(&mut self, _: &Option<Session>, msg: WebDriverMessage)
+                          -> WebDriverResult<WebDriverResponse> {
+            let mut page = self.page.lock().unwrap();
+            let input = WebElement::new("input".into());
+            match msg.command {
+                WebDriverCommand::NewSession(_) => Ok(WebDriverResponse::NewSession(
+                    NewSessionResponse::new("s1".into(), Json::Object(Default::default())))),
+                WebDriverCommand::DeleteSession => Ok(WebDriverResponse::DeleteSession),
+                WebDriverCommand::Get(params) => {
+                    page.url = params.url;
+                    Ok(WebDriverResponse::Void)
+                },
+                WebDriverCommand::GetCurrentUrl => value(page.url.clone()),
+                WebDriverCommand::GetTitle => value(format!("Typed {}", page.text)),
+                WebDriverCommand::FindElement(ref params) if params.value == "input" => {
+                    value(input)
+                },
+                WebDriverCommand::FindElements(ref params) if params.value == "input" => {
+                    value(vec![input])
+                },
+                WebDriverCommand::FindElement(_) => {
+                    Err(WebDriverError::new(ErrorStatus::NoSuchElement, "No such element"))
+                },
+                WebDriverCommand::FindElements(_) => value(Vec::<WebElement>::new()),
+                WebDriverCommand::ElementClick(ref element) if *element == input => {
+                    page.clicks += 1;
+                    Ok(WebDriverResponse::Void)
+                },
+                WebDriverCommand::ElementSendKeys(ref element, ref params) if *element == input => {
+                    page.text.push_str(&params.text);
+                    Ok(WebDriverResponse::Void)
+                },
+                WebDriverCommand::ExecuteScript(params) => value(params.args),
+                _ => Err(WebDriverError::new(ErrorStatus::UnsupportedOperation, "Not implemented"))
+            }
+        }
+
+        fn delete_session(&mut self, _: &Option<Session>) {}
+    }
+
+    fn start(page: &Arc<Mutex<Page>>) -> (ServerHandle<VoidWebDriverExtensionRoute>, Client) {
+        let server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .url_prefix("/wd/hub")
+            .start(StubHandler { page: page.clone() })
+            .unwrap();
+        let url = format!("http://{}/wd/hub", server.local_addr().unwrap());
+        (server, Client::new(&url).unwrap())
+    }
+
+    #[test]
+    fn test_session() {
+        let page = Arc::new(Mutex::new(Page::default()));
+        let (server, mut client) = start(&page);
+
+        assert_eq!(client.goto("http://example.test/").err().unwrap().error,
+                   ErrorStatus::InvalidSessionId);
+        let response = client.new_session(Client::default_session_parameters()).unwrap();
+        assert_eq!(response.sessionId, "s1");
+        assert_eq!(client.session_id(), Some("s1"));
+        assert!(client.new_session(Client::default_session_parameters()).is_err());
+
+        client.goto("http://example.test/").unwrap();
+        assert_eq!(page.lock().unwrap().url, "http://example.test/");
+        assert_eq!(client.current_url().unwrap(), "http://example.test/");
+
+        client.delete_session().unwrap();
+        assert_eq!(client.session_id(), None);
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_elements() {
+        let page = Arc::new(Mutex::new(Page::default()));
+        let (server, mut client) = start(&page);
+        client.new_session(Client::default_session_parameters()).unwrap();
+
+        let input = client.find(LocatorStrategy::CSSSelector, "input").unwrap();
+        assert_eq!(input.id, "input");
+        client.click(&input).unwrap();
+        client.send_keys(&input, "h\u{e9}llo").unwrap();
+        assert_eq!(page.lock().unwrap().clicks, 1);
+        assert_eq!(client.title().unwrap(), "Typed h\u{e9}llo");
+
+        assert_eq!(client.find_all(LocatorStrategy::CSSSelector, "input").unwrap(), vec![input]);
+        assert_eq!(client.find_all(LocatorStrategy::XPath, "//p").unwrap(), vec![]);
+        let err = client.find(LocatorStrategy::CSSSelector, "p").err().unwrap();
+        assert_eq!(err.error, ErrorStatus::NoSuchElement);
+        assert_eq!(err.message, "No such element");
+        assert!(err.stacktrace().is_some());
+
+        let err = client.click(&WebElement::new("other".into())).err().unwrap();
+        assert_eq!(err.error, ErrorStatus::UnsupportedOperation);
+
+        client.delete_session().unwrap();
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_execute() {
+        let page = Arc::new(Mutex::new(Page::default()));
+        let (server, mut client) = start(&page);
+        client.new_session(Client::default_session_parameters()).unwrap();
+
+        let args = vec![Json::U64(1), Json::String("two".into())];
+        let result = client.execute("return arguments;", args.clone()).unwrap();
+        assert_eq!(result, Json::Array(args));
+
+        client.delete_session().unwrap();
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_url() {
+        assert!(Client::new("not a url").is_err());
+        assert!(Client::new("https://localhost:4444").is_err());
+    }
+}
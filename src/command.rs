@@ -2,13 +2,13 @@ use capabilities::{SpecNewSessionParameters, LegacyNewSessionParameters,
                    CapabilitiesMatching, BrowserCapabilities, Capabilities};
 use common::{Date, Nullable, WebElement, FrameId, LocatorStrategy};
 use error::{WebDriverResult, WebDriverError, ErrorStatus};
-use httpapi::{Route, WebDriverExtensionRoute, VoidWebDriverExtensionRoute};
-use regex::Captures;
+use httpapi::{PathParams, Route, WebDriverExtensionRoute, VoidWebDriverExtensionRoute};
+use hyper::method::Method;
 use rustc_serialize::json::{ToJson, Json};
 use std::collections::BTreeMap;
 use std::default::Default;
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub enum WebDriverCommand<T: WebDriverExtensionCommand> {
     NewSession(NewSessionParameters),
     DeleteSession,
@@ -70,6 +70,19 @@ pub enum WebDriverCommand<T: WebDriverExtensionCommand> {
 
 pub trait WebDriverExtensionCommand : Clone + Send + PartialEq {
     fn parameters_json(&self) -> Option<Json>;
+
+    /// The route to send the command to, as the method and path template of
+    /// one of the extension routes, along with the values of its path
+    /// parameters other than `sessionId`.
+    ///
+    /// This is needed for [`WebDriverHttpApi::encode_request`] to encode
+    /// the command. The default implementation returns `None`.
+    ///
+    /// [`WebDriverHttpApi::encode_request`]:
+    /// ../httpapi/struct.WebDriverHttpApi.html#method.encode_request
+    fn http_route(&self) -> Option<(Method, String, PathParams)> {
+        None
+    }
 }
 
 #[derive(Clone, PartialEq)]
@@ -81,7 +94,7 @@ impl WebDriverExtensionCommand for VoidWebDriverExtensionCommand {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct WebDriverMessage <U: WebDriverExtensionRoute=VoidWebDriverExtensionRoute> {
     pub session_id: Option<String>,
     pub command: WebDriverCommand<U::Command>,
@@ -95,8 +108,8 @@ impl <U: WebDriverExtensionRoute> WebDriverMessage<U> {
         }
     }
 
-    pub fn from_http(match_type: Route<U>, params: &Captures, body: &str, requires_body: bool) -> WebDriverResult<WebDriverMessage<U>> {
-        let session_id = WebDriverMessage::<U>::get_session_id(params);
+    pub fn from_http(match_type: Route<U>, params: &PathParams, body: &str, requires_body: bool) -> WebDriverResult<WebDriverMessage<U>> {
+        let session_id = params.name("sessionId").map(|x| x.to_string());
         let body_data = if requires_body {
             debug!("Got request body {}", body);
             match Json::from_str(body) {
@@ -157,120 +170,69 @@ impl <U: WebDriverExtensionRoute> WebDriverMessage<U> {
                 WebDriverCommand::FindElements(parameters)
             },
             Route::FindElementElement => {
-                let element_id = try_opt!(params.name("elementId"),
-                                          ErrorStatus::InvalidArgument,
-                                          "Missing elementId parameter");
-                let element = WebElement::new(element_id.as_str().into());
+                let element = WebElement::new(params.get("elementId")?);
                 let parameters: LocatorParameters = try!(Parameters::from_json(&body_data));
                 WebDriverCommand::FindElementElement(element, parameters)
             },
             Route::FindElementElements => {
-                let element_id = try_opt!(params.name("elementId"),
-                                          ErrorStatus::InvalidArgument,
-                                          "Missing elementId parameter");
-                let element = WebElement::new(element_id.as_str().into());
+                let element = WebElement::new(params.get("elementId")?);
                 let parameters: LocatorParameters = try!(Parameters::from_json(&body_data));
                 WebDriverCommand::FindElementElements(element, parameters)
             },
             Route::GetActiveElement => WebDriverCommand::GetActiveElement,
             Route::IsDisplayed => {
-                let element_id = try_opt!(params.name("elementId"),
-                                          ErrorStatus::InvalidArgument,
-                                          "Missing elementId parameter");
-                let element = WebElement::new(element_id.as_str().into());
+                let element = WebElement::new(params.get("elementId")?);
                 WebDriverCommand::IsDisplayed(element)
             },
             Route::IsSelected => {
-                let element_id = try_opt!(params.name("elementId"),
-                                          ErrorStatus::InvalidArgument,
-                                          "Missing elementId parameter");
-                let element = WebElement::new(element_id.as_str().into());
+                let element = WebElement::new(params.get("elementId")?);
                 WebDriverCommand::IsSelected(element)
             },
             Route::GetElementAttribute => {
-                let element_id = try_opt!(params.name("elementId"),
-                                          ErrorStatus::InvalidArgument,
-                                          "Missing elementId parameter");
-                let element = WebElement::new(element_id.as_str().into());
-                let attr = try_opt!(params.name("name"),
-                                    ErrorStatus::InvalidArgument,
-                                    "Missing name parameter").as_str();
-                WebDriverCommand::GetElementAttribute(element, attr.into())
+                let element = WebElement::new(params.get("elementId")?);
+                let attr = params.get("name")?;
+                WebDriverCommand::GetElementAttribute(element, attr)
             },
             Route::GetElementProperty => {
-                let element_id = try_opt!(params.name("elementId"),
-                                          ErrorStatus::InvalidArgument,
-                                          "Missing elementId parameter");
-                let element = WebElement::new(element_id.as_str().into());
-                let property = try_opt!(params.name("name"),
-                                        ErrorStatus::InvalidArgument,
-                                        "Missing name parameter").as_str();
-                WebDriverCommand::GetElementProperty(element, property.into())
+                let element = WebElement::new(params.get("elementId")?);
+                let property = params.get("name")?;
+                WebDriverCommand::GetElementProperty(element, property)
             },
             Route::GetCSSValue => {
-                let element_id = try_opt!(params.name("elementId"),
-                                          ErrorStatus::InvalidArgument,
-                                          "Missing elementId parameter");
-                let element = WebElement::new(element_id.as_str().into());
-                let property = try_opt!(params.name("propertyName"),
-                                        ErrorStatus::InvalidArgument,
-                                        "Missing propertyName parameter").as_str();
-                WebDriverCommand::GetCSSValue(element, property.into())
+                let element = WebElement::new(params.get("elementId")?);
+                let property = params.get("propertyName")?;
+                WebDriverCommand::GetCSSValue(element, property)
             },
             Route::GetElementText => {
-                let element_id = try_opt!(params.name("elementId"),
-                                          ErrorStatus::InvalidArgument,
-                                          "Missing elementId parameter");
-                let element = WebElement::new(element_id.as_str().into());
+                let element = WebElement::new(params.get("elementId")?);
                 WebDriverCommand::GetElementText(element)
             },
             Route::GetElementTagName => {
-                let element_id = try_opt!(params.name("elementId"),
-                                          ErrorStatus::InvalidArgument,
-                                          "Missing elementId parameter");
-                let element = WebElement::new(element_id.as_str().into());
+                let element = WebElement::new(params.get("elementId")?);
                 WebDriverCommand::GetElementTagName(element)
             },
             Route::GetElementRect => {
-                let element_id = try_opt!(params.name("elementId"),
-                                          ErrorStatus::InvalidArgument,
-                                          "Missing elementId parameter");
-                let element = WebElement::new(element_id.as_str().into());
+                let element = WebElement::new(params.get("elementId")?);
                 WebDriverCommand::GetElementRect(element)
             },
             Route::IsEnabled => {
-                let element_id = try_opt!(params.name("elementId"),
-                                          ErrorStatus::InvalidArgument,
-                                          "Missing elementId parameter");
-                let element = WebElement::new(element_id.as_str().into());
+                let element = WebElement::new(params.get("elementId")?);
                 WebDriverCommand::IsEnabled(element)
             },
             Route::ElementClick => {
-                let element_id = try_opt!(params.name("elementId"),
-                                          ErrorStatus::InvalidArgument,
-                                          "Missing elementId parameter");
-                let element = WebElement::new(element_id.as_str().into());
+                let element = WebElement::new(params.get("elementId")?);
                 WebDriverCommand::ElementClick(element)
             },
             Route::ElementTap => {
-                let element_id = try_opt!(params.name("elementId"),
-                                          ErrorStatus::InvalidArgument,
-                                          "Missing elementId parameter");
-                let element = WebElement::new(element_id.as_str().into());
+                let element = WebElement::new(params.get("elementId")?);
                 WebDriverCommand::ElementTap(element)
             },
             Route::ElementClear => {
-                let element_id = try_opt!(params.name("elementId"),
-                                          ErrorStatus::InvalidArgument,
-                                          "Missing elementId parameter");
-                let element = WebElement::new(element_id.as_str().into());
+                let element = WebElement::new(params.get("elementId")?);
                 WebDriverCommand::ElementClear(element)
             },
             Route::ElementSendKeys => {
-                let element_id = try_opt!(params.name("elementId"),
-                                          ErrorStatus::InvalidArgument,
-                                          "Missing elementId parameter");
-                let element = WebElement::new(element_id.as_str().into());
+                let element = WebElement::new(params.get("elementId")?);
                 let parameters: SendKeysParameters = try!(Parameters::from_json(&body_data));
                 WebDriverCommand::ElementSendKeys(element, parameters)
             },
@@ -286,9 +248,7 @@ impl <U: WebDriverExtensionRoute> WebDriverMessage<U> {
                 WebDriverCommand::GetCookies
             },
             Route::GetNamedCookie => {
-                let name = try_opt!(params.name("name"),
-                                    ErrorStatus::InvalidArgument,
-                                    "Missing 'name' parameter").as_str().into();
+                let name = params.get("name")?;
                 WebDriverCommand::GetNamedCookie(name)
             },
             Route::AddCookie => {
@@ -299,9 +259,7 @@ impl <U: WebDriverExtensionRoute> WebDriverMessage<U> {
                 WebDriverCommand::DeleteCookies
             },
             Route::DeleteCookie => {
-                let name = try_opt!(params.name("name"),
-                                    ErrorStatus::InvalidArgument,
-                                    "Missing name parameter").as_str().into();
+                let name = params.get("name")?;
                 WebDriverCommand::DeleteCookie(name)
             },
             Route::PerformActions => {
@@ -326,10 +284,7 @@ impl <U: WebDriverExtensionRoute> WebDriverMessage<U> {
             },
             Route::TakeScreenshot => WebDriverCommand::TakeScreenshot,
             Route::TakeElementScreenshot =>  {
-                let element_id = try_opt!(params.name("elementId"),
-                                          ErrorStatus::InvalidArgument,
-                                          "Missing elementId parameter");
-                let element = WebElement::new(element_id.as_str().into());
+                let element = WebElement::new(params.get("elementId")?);
                 WebDriverCommand::TakeElementScreenshot(element)
             },
             Route::Status => WebDriverCommand::Status,
@@ -339,15 +294,12 @@ impl <U: WebDriverExtensionRoute> WebDriverMessage<U> {
         };
         Ok(WebDriverMessage::new(session_id, command))
     }
-
-    fn get_session_id(params: &Captures) -> Option<String> {
-        params.name("sessionId").map(|x| x.as_str().into())
-    }
 }
 
-impl <U:WebDriverExtensionRoute> ToJson for WebDriverMessage<U> {
-    fn to_json(&self) -> Json {
-        let parameters = match self.command {
+impl <T: WebDriverExtensionCommand> WebDriverCommand<T> {
+    /// The parameters of the command, as sent in the body of its request.
+    pub fn parameters_json(&self) -> Option<Json> {
+        match *self {
             WebDriverCommand::AcceptAlert |
             WebDriverCommand::CloseWindow |
             WebDriverCommand::ReleaseActions |
@@ -381,7 +333,6 @@ impl <U:WebDriverExtensionRoute> ToJson for WebDriverMessage<U> {
             WebDriverCommand::IsEnabled(_) |
             WebDriverCommand::IsSelected(_) |
             WebDriverCommand::MaximizeWindow |
-            WebDriverCommand::NewSession(_) |
             WebDriverCommand::Refresh |
             WebDriverCommand::Status |
             WebDriverCommand::SwitchToParentFrame |
@@ -399,6 +350,7 @@ impl <U:WebDriverExtensionRoute> ToJson for WebDriverMessage<U> {
             WebDriverCommand::FindElement(ref x) => Some(x.to_json()),
             WebDriverCommand::FindElements(ref x) => Some(x.to_json()),
             WebDriverCommand::Get(ref x) => Some(x.to_json()),
+            WebDriverCommand::NewSession(ref x) => Some(x.to_json()),
             WebDriverCommand::PerformActions(ref x) => Some(x.to_json()),
             WebDriverCommand::SendAlertText(ref x) => Some(x.to_json()),
             WebDriverCommand::SetTimeouts(ref x) => Some(x.to_json()),
@@ -406,6 +358,15 @@ impl <U:WebDriverExtensionRoute> ToJson for WebDriverMessage<U> {
             WebDriverCommand::SwitchToFrame(ref x) => Some(x.to_json()),
             WebDriverCommand::SwitchToWindow(ref x) => Some(x.to_json()),
             WebDriverCommand::Extension(ref x) => x.parameters_json(),
+        }
+    }
+}
+
+impl <U:WebDriverExtensionRoute> ToJson for WebDriverMessage<U> {
+    fn to_json(&self) -> Json {
+        let parameters = match self.command {
+            WebDriverCommand::NewSession(_) => None,
+            ref command => command.parameters_json(),
         };
 
         let mut data = BTreeMap::new();
@@ -426,7 +387,7 @@ pub trait Parameters: Sized {
 /// the legacy variant is used to store desiredCapabilities/requiredCapabilities
 /// parameters, and is intended to minimise breakage as we transition users to
 /// the spec design.
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub enum NewSessionParameters {
     Spec(SpecNewSessionParameters),
     Legacy(LegacyNewSessionParameters)
@@ -465,7 +426,7 @@ impl CapabilitiesMatching for NewSessionParameters {
 }
 
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct GetParameters {
     pub url: String
 }
@@ -494,7 +455,7 @@ impl ToJson for GetParameters {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct TimeoutsParameters {
     pub script: Option<u64>,
     pub page_load: Option<u64>,
@@ -558,7 +519,7 @@ impl ToJson for TimeoutsParameters {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct WindowRectParameters {
     pub x: Nullable<i64>,
     pub y: Nullable<i64>,
@@ -633,7 +594,7 @@ impl ToJson for WindowRectParameters {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct SwitchToWindowParameters {
     pub handle: String
 }
@@ -662,7 +623,7 @@ impl ToJson for SwitchToWindowParameters {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct LocatorParameters {
     pub using: LocatorStrategy,
     pub value: String
@@ -701,7 +662,7 @@ impl ToJson for LocatorParameters {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct SwitchToFrameParameters {
     pub id: FrameId
 }
@@ -729,7 +690,7 @@ impl ToJson for SwitchToFrameParameters {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct SendKeysParameters {
     pub text: String
 }
@@ -739,7 +700,8 @@ impl Parameters for SendKeysParameters {
         let data = try_opt!(body.as_object(),
                             ErrorStatus::InvalidArgument,
                             "Message body was not an object");
-        let text = try_opt!(try_opt!(data.get("text"),
+        // Before 0.26 `to_json` wrote `value`, so take that from older clients
+        let text = try_opt!(try_opt!(data.get("text").or_else(|| data.get("value")),
                                      ErrorStatus::InvalidArgument,
                                      "Missing 'text' parameter").as_string(),
                             ErrorStatus::InvalidArgument,
@@ -754,12 +716,12 @@ impl Parameters for SendKeysParameters {
 impl ToJson for SendKeysParameters {
     fn to_json(&self) -> Json {
         let mut data = BTreeMap::new();
-        data.insert("value".to_string(), self.text.to_json());
+        data.insert("text".to_string(), self.text.to_json());
         Json::Object(data)
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct JavascriptCommandParameters {
     pub script: String,
     pub args: Nullable<Vec<Json>>
@@ -807,7 +769,7 @@ impl ToJson for JavascriptCommandParameters {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct GetNamedCookieParameters {
     pub name: Nullable<String>,
 }
@@ -838,7 +800,7 @@ impl ToJson for GetNamedCookieParameters {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct AddCookieParameters {
     pub name: String,
     pub value: String,
@@ -953,7 +915,7 @@ impl ToJson for AddCookieParameters {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct TakeScreenshotParameters {
     pub element: Nullable<WebElement>
 }
@@ -986,7 +948,7 @@ impl ToJson for TakeScreenshotParameters {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct ActionsParameters {
     pub actions: Vec<ActionSequence>
 }
@@ -1022,7 +984,7 @@ impl ToJson for ActionsParameters {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct ActionSequence {
     pub id: Nullable<String>,
     pub actions: ActionsType
@@ -1088,7 +1050,7 @@ impl ToJson for ActionSequence {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub enum ActionsType {
     Null(Vec<NullActionItem>),
     Key(Vec<KeyActionItem>),
@@ -1137,7 +1099,7 @@ impl Parameters for ActionsType {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub enum PointerType {
     Mouse,
     Pen,
@@ -1178,7 +1140,7 @@ impl Default for PointerType {
     }
 }
 
-#[derive(Default, PartialEq)]
+#[derive(Clone, Default, PartialEq)]
 pub struct PointerActionParameters {
     pub pointer_type: PointerType
 }
@@ -1207,7 +1169,7 @@ impl ToJson for PointerActionParameters {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub enum NullActionItem {
     General(GeneralAction)
 }
@@ -1240,7 +1202,7 @@ impl ToJson for NullActionItem {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub enum KeyActionItem {
     General(GeneralAction),
     Key(KeyAction)
@@ -1275,7 +1237,7 @@ impl ToJson for KeyActionItem {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub enum PointerActionItem {
     General(GeneralAction),
     Pointer(PointerAction)
@@ -1309,7 +1271,7 @@ impl ToJson for PointerActionItem {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub enum GeneralAction {
     Pause(PauseAction)
 }
@@ -1332,7 +1294,7 @@ impl ToJson for GeneralAction {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct PauseAction {
     pub duration: u64
 }
@@ -1359,7 +1321,7 @@ impl ToJson for PauseAction {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub enum KeyAction {
     Up(KeyUpAction),
     Down(KeyDownAction)
@@ -1402,7 +1364,7 @@ fn validate_key_value(value_str: &str) -> WebDriverResult<char> {
     Ok(value)
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct KeyUpAction {
     pub value: char
 }
@@ -1434,7 +1396,7 @@ impl ToJson for KeyUpAction {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct KeyDownAction {
     pub value: char
 }
@@ -1465,7 +1427,7 @@ impl ToJson for KeyDownAction {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub enum PointerOrigin {
     Viewport,
     Pointer,
@@ -1506,7 +1468,7 @@ impl Default for PointerOrigin {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub enum PointerAction {
     Up(PointerUpAction),
     Down(PointerDownAction),
@@ -1544,7 +1506,7 @@ impl ToJson for PointerAction {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct PointerUpAction {
     pub button: u64,
 }
@@ -1574,7 +1536,7 @@ impl ToJson for PointerUpAction {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct PointerDownAction {
     pub button: u64,
 }
@@ -1604,7 +1566,7 @@ impl ToJson for PointerDownAction {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct PointerMoveAction {
     pub duration: Nullable<u64>,
     pub origin: PointerOrigin,
@@ -1677,8 +1639,8 @@ impl ToJson for PointerMoveAction {
 
 #[cfg(test)]
 mod tests {
-    use rustc_serialize::json::Json;
-    use super::{Nullable, Parameters, WindowRectParameters};
+    use rustc_serialize::json::{Json, ToJson};
+    use super::{Nullable, Parameters, SendKeysParameters, WindowRectParameters};
 
     #[test]
     fn test_window_rect() {
@@ -1715,4 +1677,16 @@ mod tests {
         let actual = Json::from_str(r#"{"x": 0, "width": 2}"#).unwrap();
         assert_eq!(expected, Parameters::from_json(&actual).unwrap());
     }
+
+    #[test]
+    fn test_send_keys() {
+        let params = SendKeysParameters { text: "abc".into() };
+        assert_eq!(params.to_json().to_string(), r#"{"text":"abc"}"#);
+        let decoded: SendKeysParameters = Parameters::from_json(&params.to_json()).unwrap();
+        assert_eq!(decoded.text, "abc");
+
+        let legacy = Json::from_str(r#"{"value": "abc"}"#).unwrap();
+        let decoded: SendKeysParameters = Parameters::from_json(&legacy).unwrap();
+        assert_eq!(decoded.text, "abc");
+    }
 }
@@ -142,7 +142,7 @@ impl <T> From<T> for WebElement
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 pub enum FrameId {
     Short(u16),
     Element(WebElement),
@@ -184,7 +184,7 @@ impl ToJson for FrameId {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub enum LocatorStrategy {
     CSSSelector,
     LinkText,
@@ -0,0 +1,565 @@
+//! Helpers for implementing the Perform Actions command.
+//!
+//! [`ticks`] splits `ActionsParameters` into the ticks of the spec's
+//! dispatch actions algorithm: the nth tick holds the nth action of every
+//! input source, in the order the sources were given, and lasts as long as
+//! its longest pause or pointer move. A backend dispatches each tick's
+//! actions and then waits for the rest of the tick's duration before moving
+//! on to the next one.
+//!
+//! [`InputState`] remembers what performed actions left pressed, so that
+//! the Release Actions command can undo it.
+//!
+//! [`ticks`]: fn.ticks.html
+//! [`InputState`]: struct.InputState.html
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::cmp;
+
+use command::{ActionsParameters, ActionsType, GeneralAction, KeyAction, KeyActionItem,
+              KeyUpAction, NullActionItem, PointerAction, PointerActionItem, PointerOrigin,
+              PointerType, PointerUpAction};
+use common::Nullable;
+use error::{ErrorStatus, WebDriverError, WebDriverResult};
+
+/// Actions that are dispatched together.
+#[derive(Clone, PartialEq)]
+pub struct Tick {
+    /// The longest duration of any action in the tick, in milliseconds.
+    pub duration: u64,
+    pub actions: Vec<SourceAction>,
+}
+
+/// An action of a single input source.
+#[derive(Clone, PartialEq)]
+pub struct SourceAction {
+    pub id: String,
+    pub action: Action,
+}
+
+#[derive(Clone, PartialEq)]
+pub enum Action {
+    /// Do nothing for this many milliseconds.
+    Pause(u64),
+    Key(KeyAction),
+    Pointer(PointerType, PointerAction),
+}
+
+impl Action {
+    /// How long the action takes, if it specifies a duration.
+    ///
+    /// Pointer moves without a duration take the duration of the tick
+    /// they are part of.
+    pub fn duration(&self) -> Option<u64> {
+        match *self {
+            Action::Pause(duration) => Some(duration),
+            Action::Pointer(_, PointerAction::Move(ref action)) => match action.duration {
+                Nullable::Value(duration) => Some(duration),
+                Nullable::Null => None
+            },
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+enum SourceType {
+    Null,
+    Key,
+    Pointer(PointerType),
+}
+
+fn pause(action: &GeneralAction) -> Action {
+    match *action {
+        GeneralAction::Pause(ref pause) => Action::Pause(pause.duration)
+    }
+}
+
+/// Split `parameters` into ticks.
+///
+/// Every input source must have an id, and sources that share an id must
+/// be of the same type.
+pub fn ticks(parameters: &ActionsParameters) -> WebDriverResult<Vec<Tick>> {
+    let mut sources = BTreeMap::new();
+    let mut ticks: Vec<Tick> = vec![];
+    for sequence in parameters.actions.iter() {
+        let id = match sequence.id {
+            Nullable::Value(ref id) => id,
+            Nullable::Null => return Err(WebDriverError::new(
+                ErrorStatus::InvalidArgument,
+                "Input source is missing an id"))
+        };
+        let (source_type, actions) = match sequence.actions {
+            ActionsType::Null(ref items) => {
+                (SourceType::Null,
+                 items.iter().map(|item| match *item {
+                     NullActionItem::General(ref x) => pause(x)
+                 }).collect::<Vec<_>>())
+            },
+            ActionsType::Key(ref items) => {
+                (SourceType::Key,
+                 items.iter().map(|item| match *item {
+                     KeyActionItem::General(ref x) => pause(x),
+                     KeyActionItem::Key(ref x) => Action::Key(x.clone())
+                 }).collect())
+            },
+            ActionsType::Pointer(ref parameters, ref items) => {
+                let pointer_type = &parameters.pointer_type;
+                (SourceType::Pointer(pointer_type.clone()),
+                 items.iter().map(|item| match *item {
+                     PointerActionItem::General(ref x) => pause(x),
+                     PointerActionItem::Pointer(ref x) => {
+                         Action::Pointer(pointer_type.clone(), x.clone())
+                     }
+                 }).collect())
+            }
+        };
+        if *sources.entry(id).or_insert_with(|| source_type.clone()) != source_type {
+            return Err(type_mismatch(id));
+        }
+        for (i, action) in actions.into_iter().enumerate() {
+            if ticks.len() == i {
+                ticks.push(Tick {
+                    duration: 0,
+                    actions: vec![]
+                });
+            }
+            let tick = &mut ticks[i];
+            tick.duration = cmp::max(tick.duration, action.duration().unwrap_or(0));
+            tick.actions.push(SourceAction {
+                id: id.clone(),
+                action: action
+            });
+        }
+    }
+    Ok(ticks)
+}
+
+/// Positions of a pointer moving in a straight line, at each `interval`
+/// milliseconds over `duration` milliseconds.
+///
+/// Each item is the time since the start of the move, and the x and y
+/// position at that time. The last item is always `to` at `duration`, so a
+/// move with no duration yields just the target position.
+pub fn interpolate(from: (i64, i64), to: (i64, i64), duration: u64, interval: u64)
+                   -> Interpolation {
+    Interpolation {
+        from: from,
+        to: to,
+        duration: duration,
+        interval: cmp::max(interval, 1),
+        elapsed: Some(0),
+    }
+}
+
+/// Iterator returned by [`interpolate`].
+///
+/// [`interpolate`]: fn.interpolate.html
+#[derive(Clone, Debug)]
+pub struct Interpolation {
+    from: (i64, i64),
+    to: (i64, i64),
+    duration: u64,
+    interval: u64,
+    elapsed: Option<u64>,
+}
+
+impl Iterator for Interpolation {
+    type Item = (u64, i64, i64);
+
+    fn next(&mut self) -> Option<(u64, i64, i64)> {
+        let elapsed = cmp::min(self.elapsed? + self.interval, self.duration);
+        if elapsed == self.duration {
+            self.elapsed = None;
+            return Some((elapsed, self.to.0, self.to.1));
+        }
+        self.elapsed = Some(elapsed);
+        let position = |from: i64, to: i64| {
+            from + (to - from) * elapsed as i64 / self.duration as i64
+        };
+        Some((elapsed, position(self.from.0, self.to.0), position(self.from.1, self.to.1)))
+    }
+}
+
+/// State of a key input source.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeyState {
+    pub pressed: BTreeSet<char>,
+}
+
+impl KeyState {
+    fn any_pressed(&self, keys: &[char]) -> bool {
+        keys.iter().any(|key| self.pressed.contains(key))
+    }
+
+    pub fn shift(&self) -> bool {
+        self.any_pressed(&['\u{E008}', '\u{E050}'])
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.any_pressed(&['\u{E009}', '\u{E051}'])
+    }
+
+    pub fn alt(&self) -> bool {
+        self.any_pressed(&['\u{E00A}', '\u{E052}'])
+    }
+
+    pub fn meta(&self) -> bool {
+        self.any_pressed(&['\u{E03D}', '\u{E053}'])
+    }
+}
+
+/// State of a pointer input source.
+#[derive(Clone, PartialEq)]
+pub struct PointerState {
+    pub pointer_type: PointerType,
+    pub pressed: BTreeSet<u64>,
+    pub x: i64,
+    pub y: i64,
+}
+
+#[derive(Clone, PartialEq)]
+pub enum SourceState {
+    Null,
+    Key(KeyState),
+    Pointer(PointerState),
+}
+
+/// The input state table of a session.
+///
+/// Backends update it with every action they dispatch, and use
+/// [`release`] to get the actions that undo them for Release Actions.
+///
+/// [`release`]: #method.release
+#[derive(Clone, Default)]
+pub struct InputState {
+    sources: BTreeMap<String, SourceState>,
+    /// Actions that undo presses, in the order of the presses.
+    cancel_list: Vec<SourceAction>,
+}
+
+impl InputState {
+    pub fn new() -> InputState {
+        Default::default()
+    }
+
+    pub fn source(&self, id: &str) -> Option<&SourceState> {
+        self.sources.get(id)
+    }
+
+    /// Update the state for all of `parameters`, as if its ticks had been
+    /// dispatched in order.
+    ///
+    /// The parameters are checked against the existing sources first, so
+    /// the state is left unchanged if they are invalid.
+    pub fn perform(&mut self, parameters: &ActionsParameters) -> WebDriverResult<()> {
+        let ticks = ticks(parameters)?;
+        for sequence in parameters.actions.iter() {
+            let id = match sequence.id {
+                Nullable::Value(ref id) => id,
+                Nullable::Null => continue
+            };
+            let matches = match (self.sources.get(id), &sequence.actions) {
+                (None, _) |
+                (Some(&SourceState::Null), &ActionsType::Null(_)) |
+                (Some(&SourceState::Key(_)), &ActionsType::Key(_)) => true,
+                (Some(&SourceState::Pointer(ref state)), &ActionsType::Pointer(ref x, _)) => {
+                    state.pointer_type == x.pointer_type
+                },
+                _ => false
+            };
+            if !matches {
+                return Err(type_mismatch(id));
+            }
+        }
+
+        for sequence in parameters.actions.iter() {
+            if let (&Nullable::Value(ref id), &ActionsType::Null(_)) =
+                (&sequence.id, &sequence.actions) {
+                self.sources.entry(id.clone()).or_insert(SourceState::Null);
+            }
+        }
+        for tick in ticks.iter() {
+            for action in tick.actions.iter() {
+                self.update(action)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Update the state for a single dispatched action.
+    ///
+    /// Moves relative to an element can't be resolved here, so they leave
+    /// the position unchanged; backends should follow them with
+    /// `set_position`.
+    pub fn update(&mut self, action: &SourceAction) -> WebDriverResult<()> {
+        let id = &action.id;
+        match action.action {
+            Action::Pause(_) => {},
+            Action::Key(ref key_action) => {
+                let state = match *self.sources.entry(id.clone())
+                    .or_insert_with(|| SourceState::Key(Default::default())) {
+                    SourceState::Key(ref mut state) => state,
+                    _ => return Err(type_mismatch(id))
+                };
+                match *key_action {
+                    KeyAction::Down(ref x) => {
+                        if state.pressed.insert(x.value) {
+                            self.cancel_list.push(SourceAction {
+                                id: id.clone(),
+                                action: Action::Key(KeyAction::Up(KeyUpAction { value: x.value }))
+                            });
+                        }
+                    },
+                    KeyAction::Up(ref x) => {
+                        if state.pressed.remove(&x.value) {
+                            self.cancel_list.retain(|cancel| {
+                                cancel.id != *id || cancel.action != Action::Key(
+                                    KeyAction::Up(KeyUpAction { value: x.value }))
+                            });
+                        }
+                    }
+                }
+            },
+            Action::Pointer(ref pointer_type, ref pointer_action) => {
+                let state = match *self.sources.entry(id.clone())
+                    .or_insert_with(|| SourceState::Pointer(PointerState {
+                        pointer_type: pointer_type.clone(),
+                        pressed: BTreeSet::new(),
+                        x: 0,
+                        y: 0,
+                    })) {
+                    SourceState::Pointer(ref mut state) if state.pointer_type == *pointer_type => {
+                        state
+                    },
+                    _ => return Err(type_mismatch(id))
+                };
+                let cancel = |button| SourceAction {
+                    id: id.clone(),
+                    action: Action::Pointer(pointer_type.clone(),
+                                            PointerAction::Up(PointerUpAction { button: button }))
+                };
+                match *pointer_action {
+                    PointerAction::Down(ref x) => {
+                        if state.pressed.insert(x.button) {
+                            self.cancel_list.push(cancel(x.button));
+                        }
+                    },
+                    PointerAction::Up(ref x) => {
+                        if state.pressed.remove(&x.button) {
+                            let cancel = cancel(x.button);
+                            self.cancel_list.retain(|x| *x != cancel);
+                        }
+                    },
+                    PointerAction::Move(ref x) => {
+                        let offset = |value: &Nullable<i64>| match *value {
+                            Nullable::Value(value) => value,
+                            Nullable::Null => 0
+                        };
+                        let (dx, dy) = (offset(&x.x), offset(&x.y));
+                        match x.origin {
+                            PointerOrigin::Viewport => {
+                                state.x = dx;
+                                state.y = dy;
+                            },
+                            PointerOrigin::Pointer => {
+                                state.x += dx;
+                                state.y += dy;
+                            },
+                            PointerOrigin::Element(_) => {}
+                        }
+                    },
+                    PointerAction::Cancel => {
+                        state.pressed.clear();
+                        self.cancel_list.retain(|x| x.id != *id);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the position of the pointer source `id` in the viewport.
+    pub fn set_position(&mut self, id: &str, x: i64, y: i64) {
+        if let Some(&mut SourceState::Pointer(ref mut state)) = self.sources.get_mut(id) {
+            state.x = x;
+            state.y = y;
+        }
+    }
+
+    /// Actions that release everything still pressed, most recent press
+    /// first, and reset the state.
+    ///
+    /// The actions are dispatched together in a single tick with no
+    /// duration.
+    pub fn release(&mut self) -> Vec<SourceAction> {
+        self.sources.clear();
+        let mut actions = self.cancel_list.split_off(0);
+        actions.reverse();
+        actions
+    }
+}
+
+fn type_mismatch(id: &str) -> WebDriverError {
+    WebDriverError::new(ErrorStatus::InvalidArgument,
+                        format!("Input source {} was used with different types", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json::Json;
+
+    use actions::Actions;
+    use command::{ActionsParameters, KeyAction, KeyDownAction, KeyUpAction, Parameters,
+                  PointerAction, PointerDownAction, PointerType, PointerUpAction};
+    use common::WebElement;
+    use error::ErrorStatus;
+    use super::*;
+
+    #[test]
+    fn test_ticks() {
+        let parameters = Actions::new()
+            .key("keyboard").down('a')
+            .concurrently(|actions| {
+                actions.pointer("finger", PointerType::Touch)
+                    .move_to(0, 0).press(0).move_with(Default::default(), 10, 10, 250)
+                    .pause(100)
+            })
+            .key("keyboard").pause(50)
+            .build()
+            .unwrap();
+        let ticks = ticks(&parameters).unwrap();
+        let durations = ticks.iter().map(|tick| tick.duration).collect::<Vec<_>>();
+        assert_eq!(durations, vec![0, 0, 0, 250, 100, 50]);
+        assert!(ticks.iter().all(|tick| tick.actions.len() == 2));
+
+        let second = &ticks[1].actions;
+        assert_eq!(second[0].id, "keyboard");
+        assert!(second[0].action == Action::Pause(0));
+        assert_eq!(second[1].id, "finger");
+        assert_eq!(second[1].action.duration(), None);
+
+        let third = &ticks[2].actions;
+        assert!(third[1].action == Action::Pointer(
+            PointerType::Touch, PointerAction::Down(PointerDownAction { button: 0 })));
+        assert!(ticks[0].actions[0].action == Action::Key(
+            KeyAction::Down(KeyDownAction { value: 'a' })));
+    }
+
+    #[test]
+    fn test_ticks_uneven() {
+        let parameters = ActionsParameters::from_json(&Json::from_str(r#"{"actions": [
+            {"id": "a", "type": "none", "actions": [{"type": "pause", "duration": 5}]},
+            {"id": "b", "type": "key", "actions": [
+                {"type": "keyDown", "value": "x"},
+                {"type": "keyUp", "value": "x"}]}]}"#).unwrap()).unwrap();
+        let ticks = ticks(&parameters).unwrap();
+        assert_eq!(ticks.len(), 2);
+        assert_eq!(ticks[0].duration, 5);
+        assert_eq!(ticks[1].actions.len(), 1);
+        assert_eq!(ticks[1].actions[0].id, "b");
+    }
+
+    #[test]
+    fn test_ticks_invalid() {
+        let parameters = ActionsParameters::from_json(&Json::from_str(r#"{"actions": [
+            {"id": "a", "type": "pointer", "actions": []},
+            {"id": "a", "type": "pointer", "parameters": {"pointerType": "pen"},
+             "actions": []}]}"#).unwrap()).unwrap();
+        assert_eq!(ticks(&parameters).err().unwrap().error, ErrorStatus::InvalidArgument);
+
+        let parameters = ActionsParameters::from_json(&Json::from_str(r#"{"actions": [
+            {"type": "key", "actions": []}]}"#).unwrap()).unwrap();
+        assert_eq!(ticks(&parameters).err().unwrap().error, ErrorStatus::InvalidArgument);
+    }
+
+    #[test]
+    fn test_interpolate() {
+        assert_eq!(interpolate((0, 100), (100, 0), 100, 30).collect::<Vec<_>>(),
+                   vec![(30, 30, 70), (60, 60, 40), (90, 90, 10), (100, 100, 0)]);
+        assert_eq!(interpolate((5, 5), (-5, 15), 0, 16).collect::<Vec<_>>(),
+                   vec![(0, -5, 15)]);
+        assert_eq!(interpolate((0, 0), (10, 0), 3, 0).collect::<Vec<_>>(),
+                   vec![(1, 3, 0), (2, 6, 0), (3, 10, 0)]);
+    }
+
+    #[test]
+    fn test_input_state() {
+        let mut state = InputState::new();
+        state.perform(&Actions::new()
+                      .key("keyboard").down('\u{E008}').send_keys("a").down('b')
+                      .pointer("mouse", PointerType::Mouse).move_to(10, 20).press(0).press(2)
+                      .move_by(5, -5).release(0)
+                      .build().unwrap()).unwrap();
+        match state.source("keyboard") {
+            Some(&SourceState::Key(ref keys)) => {
+                assert_eq!(keys.pressed.iter().cloned().collect::<Vec<_>>(),
+                           vec!['b', '\u{E008}']);
+                assert!(keys.shift());
+                assert!(!keys.ctrl());
+            },
+            _ => panic!("Missing key source")
+        }
+        match state.source("mouse") {
+            Some(&SourceState::Pointer(ref pointer)) => {
+                assert_eq!(pointer.pressed.iter().cloned().collect::<Vec<_>>(), vec![2]);
+                assert_eq!((pointer.x, pointer.y), (15, 15));
+            },
+            _ => panic!("Missing pointer source")
+        }
+
+        let release = state.release();
+        let expected = vec![
+            SourceAction {
+                id: "mouse".to_string(),
+                action: Action::Pointer(PointerType::Mouse,
+                                        PointerAction::Up(PointerUpAction { button: 2 }))
+            },
+            SourceAction {
+                id: "keyboard".to_string(),
+                action: Action::Key(KeyAction::Up(KeyUpAction { value: 'b' }))
+            },
+            SourceAction {
+                id: "keyboard".to_string(),
+                action: Action::Key(KeyAction::Up(KeyUpAction { value: '\u{E008}' }))
+            },
+        ];
+        assert!(release == expected);
+        assert!(state.source("keyboard").is_none());
+        assert!(state.release().is_empty());
+    }
+
+    #[test]
+    fn test_input_state_sources() {
+        let mut state = InputState::new();
+        let element = WebElement::new("a".to_string());
+        state.perform(&Actions::new()
+                      .pause(10)
+                      .pointer("pen", PointerType::Pen).move_to(1, 2).move_to_element(&element)
+                      .build().unwrap()).unwrap();
+        assert!(state.source("none") == Some(&SourceState::Null));
+        state.set_position("pen", 7, 8);
+        match state.source("pen") {
+            Some(&SourceState::Pointer(ref pointer)) => {
+                assert_eq!((pointer.x, pointer.y), (7, 8));
+            },
+            _ => panic!("Missing pointer source")
+        }
+
+        let actions = Actions::new().key("pen").down('a').build().unwrap();
+        let err = state.perform(&actions).err().unwrap();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+        let err = state.perform(&Actions::new().pointer("pen", PointerType::Touch).press(0)
+                                .build().unwrap()).err().unwrap();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+
+        // Nothing is applied when a later source doesn't match
+        let err = state.perform(&Actions::new()
+                                .key("keyboard").down('a')
+                                .pointer("none", PointerType::Mouse).press(0)
+                                .build().unwrap()).err().unwrap();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+        assert!(state.source("keyboard").is_none());
+        assert!(state.release().is_empty());
+    }
+}
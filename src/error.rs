@@ -116,6 +116,12 @@ pub enum ErrorStatus {
     /// Setting the cookie’s value could not be done.
     UnableToSetCookie,
 
+    /// A request to the server did not carry valid credentials.
+    ///
+    /// This is not a WebDriver error; it is only returned by servers that
+    /// require authentication.
+    Unauthorized,
+
     /// A modal dialogue was open, blocking this operation.
     UnexpectedAlertOpen,
 
@@ -168,6 +174,7 @@ impl ErrorStatus {
             ErrorStatus::Timeout => "timeout",
             ErrorStatus::UnableToCaptureScreen => "unable to capture screen",
             ErrorStatus::UnableToSetCookie => "unable to set cookie",
+            ErrorStatus::Unauthorized => "unauthorized",
             ErrorStatus::UnexpectedAlertOpen => "unexpected alert open",
             ErrorStatus::UnknownCommand |
             ErrorStatus::UnknownError => "unknown error",
@@ -177,6 +184,42 @@ impl ErrorStatus {
         }
     }
 
+    /// The status for an error code, as given in the `error` field of an
+    /// error response. Unrecognised codes are treated as `UnknownError`.
+    pub fn from_error_code(code: &str) -> ErrorStatus {
+        match code {
+            "element click intercepted" => ErrorStatus::ElementClickIntercepted,
+            "element not interactable" => ErrorStatus::ElementNotInteractable,
+            "element not selectable" => ErrorStatus::ElementNotSelectable,
+            "insecure certificate" => ErrorStatus::InsecureCertificate,
+            "invalid argument" => ErrorStatus::InvalidArgument,
+            "invalid cookie domain" => ErrorStatus::InvalidCookieDomain,
+            "invalid coordinates" => ErrorStatus::InvalidCoordinates,
+            "invalid element state" => ErrorStatus::InvalidElementState,
+            "invalid selector" => ErrorStatus::InvalidSelector,
+            "invalid session id" => ErrorStatus::InvalidSessionId,
+            "javascript error" => ErrorStatus::JavascriptError,
+            "move target out of bounds" => ErrorStatus::MoveTargetOutOfBounds,
+            "no such alert" => ErrorStatus::NoSuchAlert,
+            "no such cookie" => ErrorStatus::NoSuchCookie,
+            "no such element" => ErrorStatus::NoSuchElement,
+            "no such frame" => ErrorStatus::NoSuchFrame,
+            "no such window" => ErrorStatus::NoSuchWindow,
+            "script timeout" => ErrorStatus::ScriptTimeout,
+            "session not created" => ErrorStatus::SessionNotCreated,
+            "stale element reference" => ErrorStatus::StaleElementReference,
+            "timeout" => ErrorStatus::Timeout,
+            "unable to capture screen" => ErrorStatus::UnableToCaptureScreen,
+            "unable to set cookie" => ErrorStatus::UnableToSetCookie,
+            "unauthorized" => ErrorStatus::Unauthorized,
+            "unexpected alert open" => ErrorStatus::UnexpectedAlertOpen,
+            "unknown method" => ErrorStatus::UnknownMethod,
+            "unknown command" => ErrorStatus::UnknownPath,
+            "unsupported operation" => ErrorStatus::UnsupportedOperation,
+            _ => ErrorStatus::UnknownError,
+        }
+    }
+
     pub fn http_status(&self) -> StatusCode {
         match *self {
             ErrorStatus::ElementClickIntercepted => StatusCode::BadRequest,
@@ -202,6 +245,7 @@ impl ErrorStatus {
             ErrorStatus::Timeout => StatusCode::RequestTimeout,
             ErrorStatus::UnableToCaptureScreen => StatusCode::BadRequest,
             ErrorStatus::UnableToSetCookie => StatusCode::InternalServerError,
+            ErrorStatus::Unauthorized => StatusCode::Unauthorized,
             ErrorStatus::UnexpectedAlertOpen => StatusCode::InternalServerError,
             ErrorStatus::UnknownCommand => StatusCode::NotFound,
             ErrorStatus::UnknownError => StatusCode::InternalServerError,
@@ -219,6 +263,7 @@ pub struct WebDriverError {
     pub error: ErrorStatus,
     pub message: Cow<'static, str>,
     pub backtrace: Backtrace,
+    stacktrace: Option<String>,
     pub delete_session: bool,
 }
 
@@ -230,10 +275,48 @@ impl WebDriverError {
             error: error,
             message: message.into(),
             backtrace: Backtrace::new(),
+            stacktrace: None,
             delete_session: false,
         }
     }
 
+    /// Set the stacktrace reported by the remote end, for errors decoded
+    /// from a response. This is sent in place of `backtrace` when the error
+    /// is encoded again.
+    pub fn with_stacktrace<S: Into<Option<String>>>(mut self, stacktrace: S) -> WebDriverError {
+        self.stacktrace = stacktrace.into();
+        self
+    }
+
+    /// The stacktrace reported by the remote end, if any.
+    pub fn stacktrace(&self) -> Option<&str> {
+        self.stacktrace.as_ref().map(|x| &x[..])
+    }
+
+    /// Decode an error from the body of an error response, i.e.
+    /// `{"value": {"error": ..., "message": ..., "stacktrace": ...}}`.
+    ///
+    /// The `value` wrapper may be left out.
+    pub fn from_json(body: &Json) -> WebDriverResult<WebDriverError> {
+        let data = match body.find("value") {
+            Some(value) => value,
+            None => body
+        };
+        let data = try_opt!(data.as_object(),
+                            ErrorStatus::UnknownError,
+                            "Error response was not an object");
+        let code = try_opt!(data.get("error").and_then(|x| x.as_string()),
+                            ErrorStatus::UnknownError,
+                            "Error response did not have an error code");
+        let message = match data.get("message") {
+            Some(&Json::String(ref message)) => message.clone(),
+            _ => String::new()
+        };
+        let stacktrace = data.get("stacktrace").and_then(|x| x.as_string()).map(|x| x.to_string());
+        Ok(WebDriverError::new(ErrorStatus::from_error_code(code), message)
+           .with_stacktrace(stacktrace))
+    }
+
     pub fn error_code(&self) -> &'static str {
         self.error.error_code()
     }
@@ -252,8 +335,11 @@ impl ToJson for WebDriverError {
         let mut data = BTreeMap::new();
         data.insert("error".into(), self.error_code().to_json());
         data.insert("message".into(), self.message.to_json());
-        data.insert("stacktrace".into(),
-                    format!("{:?}", self.backtrace).to_json());
+        let stacktrace = match self.stacktrace {
+            Some(ref stacktrace) => stacktrace.clone(),
+            None => format!("{:?}", self.backtrace)
+        };
+        data.insert("stacktrace".into(), stacktrace.to_json());
         let mut wrapper = BTreeMap::new();
         wrapper.insert("value".into(), Json::Object(data));
         Json::Object(wrapper)
@@ -305,3 +391,44 @@ impl From<Box<Error>> for WebDriverError {
         WebDriverError::new(ErrorStatus::UnknownError, err.description().to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json::{Json, ToJson};
+
+    use super::{ErrorStatus, WebDriverError};
+
+    #[test]
+    fn test_from_error_code() {
+        let statuses = vec![ErrorStatus::ElementClickIntercepted, ErrorStatus::InvalidSessionId,
+                            ErrorStatus::JavascriptError, ErrorStatus::NoSuchElement,
+                            ErrorStatus::StaleElementReference, ErrorStatus::Timeout,
+                            ErrorStatus::UnknownError, ErrorStatus::UnknownMethod,
+                            ErrorStatus::UnknownPath, ErrorStatus::UnsupportedOperation];
+        for status in statuses {
+            assert_eq!(ErrorStatus::from_error_code(status.error_code()), status);
+        }
+        assert_eq!(ErrorStatus::from_error_code("no such shadow root"), ErrorStatus::UnknownError);
+    }
+
+    #[test]
+    fn test_from_json() {
+        let err = WebDriverError::new(ErrorStatus::NoSuchWindow, "Window was closed");
+        let decoded = WebDriverError::from_json(&err.to_json()).unwrap();
+        assert_eq!(decoded.error, ErrorStatus::NoSuchWindow);
+        assert_eq!(decoded.message, "Window was closed");
+        assert_eq!(decoded.stacktrace(), Some(&format!("{:?}", err.backtrace)[..]));
+
+        // The remote stacktrace is passed on when encoding the error again.
+        let body = Json::from_str(r#"{"error": "timeout", "stacktrace": "remote"}"#).unwrap();
+        let decoded = WebDriverError::from_json(&body).unwrap();
+        assert_eq!(decoded.error, ErrorStatus::Timeout);
+        assert_eq!(decoded.message, "");
+        assert_eq!(decoded.to_json().find_path(&["value", "stacktrace"]).unwrap().as_string(),
+                   Some("remote"));
+        assert_eq!(decoded.with_stacktrace(None).stacktrace(), None);
+
+        assert!(WebDriverError::from_json(&Json::from_str(r#"{"value": {}}"#).unwrap()).is_err());
+        assert!(WebDriverError::from_json(&Json::Null).is_err());
+    }
+}
@@ -1,12 +1,17 @@
-use regex::{Regex, Captures};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
 use rustc_serialize::json::Json;
 
 use hyper::method::Method;
 use hyper::method::Method::{Get, Post, Delete};
+use url::percent_encoding::{self, EncodeSet};
 
 use command::{WebDriverCommand, WebDriverMessage, WebDriverExtensionCommand,
               VoidWebDriverExtensionCommand};
+use common::WebElement;
 use error::{WebDriverResult, WebDriverError, ErrorStatus};
+use router::Router;
 
 fn standard_routes<U:WebDriverExtensionRoute>() -> Vec<(Method, &'static str, Route<U>)> {
     return vec![(Post, "/session", Route::NewSession),
@@ -69,7 +74,7 @@ fn standard_routes<U:WebDriverExtensionRoute>() -> Vec<(Method, &'static str, Ro
                 (Get, "/status", Route::Status),]
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum Route<U:WebDriverExtensionRoute> {
     NewSession,
     DeleteSession,
@@ -135,7 +140,13 @@ pub enum Route<U:WebDriverExtensionRoute> {
 pub trait WebDriverExtensionRoute : Clone + Send + PartialEq {
     type Command: WebDriverExtensionCommand + 'static;
 
-    fn command(&self, &Captures, &Json) -> WebDriverResult<WebDriverCommand<Self::Command>>;
+    /// Build the command for a request to this route.
+    ///
+    /// Since 0.26 the path parameters are given as [`PathParams`], already
+    /// percent-decoded, rather than as regex `Captures`.
+    ///
+    /// [`PathParams`]: struct.PathParams.html
+    fn command(&self, params: &PathParams, body: &Json) -> WebDriverResult<WebDriverCommand<Self::Command>>;
 }
 
 #[derive(Clone, PartialEq)]
@@ -144,97 +155,703 @@ pub struct VoidWebDriverExtensionRoute;
 impl WebDriverExtensionRoute for VoidWebDriverExtensionRoute {
     type Command = VoidWebDriverExtensionCommand;
 
-    fn command(&self, _:&Captures, _:&Json) -> WebDriverResult<WebDriverCommand<VoidWebDriverExtensionCommand>> {
+    fn command(&self, _:&PathParams, _:&Json) -> WebDriverResult<WebDriverCommand<VoidWebDriverExtensionCommand>> {
         panic!("No extensions implemented");
     }
 }
 
-#[derive(Clone)]
-struct RequestMatcher<U: WebDriverExtensionRoute> {
-    method: Method,
-    path_regexp: Regex,
-    match_type: Route<U>
+/// Path parameters of a request, such as the `{sessionId}` in
+/// `/session/{sessionId}/url`, with any percent-encoding decoded.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PathParams {
+    params: Vec<(String, String)>,
 }
 
-impl <U: WebDriverExtensionRoute> RequestMatcher<U> {
-    pub fn new(method: Method, path: &str, match_type: Route<U>) -> RequestMatcher<U> {
-        let path_regexp = RequestMatcher::<U>::compile_path(path);
-        RequestMatcher {
-            method: method,
-            path_regexp: path_regexp,
-            match_type: match_type
+impl PathParams {
+    pub fn new(params: Vec<(String, String)>) -> PathParams {
+        PathParams {
+            params: params,
         }
     }
 
-    pub fn get_match<'t>(&'t self, method: Method, path: &'t str) -> (bool, Option<Captures>) {
-        let captures = self.path_regexp.captures(path);
-        (method == self.method, captures)
+    /// The value of the parameter `name`, if the route has one.
+    pub fn name(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|x| x.0 == name).map(|x| &x.1[..])
     }
 
-    fn compile_path(path: &str) -> Regex {
-        let mut rv = String::new();
-        rv.push_str("^");
-        let components = path.split('/');
-        for component in components {
-            if component.starts_with("{") {
-                if !component.ends_with("}") {
-                    panic!("Invalid url pattern")
-                }
-                rv.push_str(&format!("(?P<{}>[^/]+)/", &component[1..component.len()-1])[..]);
-            } else {
-                rv.push_str(&format!("{}/", component)[..]);
-            }
-        }
-        //Remove the trailing /
-        rv.pop();
-        rv.push_str("$");
-        //This will fail at runtime if the regexp is invalid
-        Regex::new(&rv[..]).unwrap()
+    /// The value of the parameter `name` parsed as a `T`.
+    ///
+    /// A missing parameter or one that doesn't parse is an
+    /// `InvalidArgument` error.
+    pub fn get<T: FromStr>(&self, name: &str) -> WebDriverResult<T> {
+        let value = try_opt!(self.name(name),
+                             ErrorStatus::InvalidArgument,
+                             format!("Missing {} parameter", name));
+        value.parse().map_err(|_| {
+            WebDriverError::new(ErrorStatus::InvalidArgument,
+                                format!("Invalid {} parameter: {}", name, value))
+        })
     }
 }
 
+/// The route matched by a request.
+pub struct RouteMatch<'a, U: 'a + WebDriverExtensionRoute> {
+    /// The path template of the route, e.g. `/session/{sessionId}/url`.
+    pub template: &'a str,
+    pub route: &'a Route<U>,
+    pub params: PathParams,
+}
+
 pub struct WebDriverHttpApi<U: WebDriverExtensionRoute> {
-    routes: Vec<(Method, RequestMatcher<U>)>,
+    prefix: String,
+    router: Router<Route<U>>,
+    routes: Vec<(Method, String, Route<U>)>,
 }
 
 impl <U: WebDriverExtensionRoute> WebDriverHttpApi<U> {
+    /// Create the API with the standard routes and `extension_routes`.
+    ///
+    /// Extension routes that are malformed or conflict with another route
+    /// are logged and left out. Use [`try_new`] to get an error for them
+    /// instead.
+    ///
+    /// [`try_new`]: #method.try_new
     pub fn new(extension_routes: &[(Method, &str, U)]) -> WebDriverHttpApi<U> {
+        let mut rv = WebDriverHttpApi::standard();
+        for &(ref method, ref url, ref extension_route) in extension_routes.iter() {
+            if let Err(err) = rv.add(method.clone(), *url, Route::Extension(extension_route.clone())) {
+                error!("Ignoring extension route {} {}: {}", method, url, err.message);
+            }
+        };
+        rv
+    }
+
+    /// Create the API with the standard routes and `extension_routes`.
+    ///
+    /// Path parameters in routes are written `{name}`, and may be restricted
+    /// to integers with `{name:int}` or `{name:uint}`. Routes that are
+    /// malformed, or that have the same method and path as another route
+    /// apart from the names of their parameters, are an `InvalidArgument`
+    /// error.
+    pub fn try_new(extension_routes: &[(Method, &str, U)]) -> WebDriverResult<WebDriverHttpApi<U>> {
+        let mut rv = WebDriverHttpApi::standard();
+        for &(ref method, ref url, ref extension_route) in extension_routes.iter() {
+            rv.add(method.clone(), *url, Route::Extension(extension_route.clone()))?;
+        };
+        Ok(rv)
+    }
+
+    fn standard() -> WebDriverHttpApi<U> {
         let mut rv = WebDriverHttpApi::<U> {
+            prefix: String::new(),
+            router: Router::new(),
             routes: vec![],
         };
         debug!("Creating routes");
         for &(ref method, ref url, ref match_type) in standard_routes::<U>().iter() {
-            rv.add(method.clone(), *url, (*match_type).clone());
-        };
-        for &(ref method, ref url, ref extension_route) in extension_routes.iter() {
-            rv.add(method.clone(), *url, Route::Extension(extension_route.clone()));
+            rv.add(method.clone(), *url, (*match_type).clone())
+                .expect("Standard routes are valid");
         };
         rv
     }
 
-    fn add(&mut self, method: Method, path: &str, match_type: Route<U>) {
-        let http_matcher = RequestMatcher::new(method.clone(), path, match_type);
-        self.routes.push((method, http_matcher));
+    /// Serve the API under a base path such as `/wd/hub`, as used by
+    /// Selenium grids, rather than at the root.
+    pub fn with_prefix(mut self, prefix: &str) -> WebDriverHttpApi<U> {
+        let prefix = prefix.trim_end_matches('/');
+        self.prefix = if prefix.is_empty() || prefix.starts_with('/') {
+            prefix.to_string()
+        } else {
+            format!("/{}", prefix)
+        };
+        self
+    }
+
+    fn add(&mut self, method: Method, path: &str, match_type: Route<U>) -> WebDriverResult<()> {
+        self.router.insert(method.clone(), path, match_type.clone()).map_err(|err| {
+            WebDriverError::new(ErrorStatus::InvalidArgument, err)
+        })?;
+        self.routes.push((method, path.to_string(), match_type));
+        Ok(())
+    }
+
+    /// The method and path template of every route, standard routes first.
+    pub fn routes(&self) -> Vec<(&Method, &str)> {
+        self.routes.iter().map(|&(ref method, ref path, _)| (method, &path[..])).collect()
     }
 
     pub fn decode_request(&self, method: Method, path: &str, body: &str) -> WebDriverResult<WebDriverMessage<U>> {
-        let mut error = ErrorStatus::UnknownPath;
-        for &(ref match_method, ref matcher) in self.routes.iter() {
-            if method == *match_method {
-                let (method_match, captures) = matcher.get_match(method.clone(), path);
-                if captures.is_some() {
-                    if method_match {
-                        return WebDriverMessage::from_http(matcher.match_type.clone(),
-                                                           &captures.unwrap(),
-                                                           body,
-                                                           method == Post)
-                    } else {
-                        error = ErrorStatus::UnknownMethod;
-                    }
+        self.decode_request_route(method, path, body).1
+    }
+
+    /// Remove the base path and any trailing slashes from `path`, returning
+    /// `None` if `path` isn't under the base path.
+    fn route_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+        if !path.starts_with(&self.prefix[..]) {
+            return None
+        }
+        let rest = path[self.prefix.len()..].trim_end_matches('/');
+        if rest.is_empty() {
+            Some("/")
+        } else if rest.starts_with('/') {
+            Some(rest)
+        } else {
+            None
+        }
+    }
+
+    /// Find the route for a request.
+    ///
+    /// Fails with `UnknownMethod` if the path only exists for other methods,
+    /// which are given by [`allowed_methods`].
+    ///
+    /// [`allowed_methods`]: #method.allowed_methods
+    pub fn match_route(&self, method: &Method, path: &str) -> WebDriverResult<RouteMatch<'_, U>> {
+        let route_path = match self.route_path(path) {
+            Some(route_path) => route_path,
+            None => {
+                return Err(WebDriverError::new(ErrorStatus::UnknownPath,
+                                               format!("{} is not under {}", path, self.prefix)))
+            }
+        };
+        match self.router.lookup(method, route_path) {
+            Ok((endpoint, values)) => {
+                let mut params = Vec::with_capacity(values.len());
+                for (name, value) in endpoint.params.iter().zip(values) {
+                    params.push((name.clone(), percent_decode(value)?));
                 }
+                Ok(RouteMatch {
+                    template: &endpoint.template,
+                    route: &endpoint.value,
+                    params: PathParams::new(params),
+                })
+            },
+            Err(ref allowed) if allowed.is_empty() => {
+                Err(WebDriverError::new(ErrorStatus::UnknownPath,
+                                        format!("{} {} did not match a known command",
+                                                method, path)))
+            },
+            Err(allowed) => {
+                let allowed = allowed.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+                Err(WebDriverError::new(ErrorStatus::UnknownMethod,
+                                        format!("{} is not supported for {}, only {}",
+                                                method, path, allowed.join(", "))))
             }
         }
-        Err(WebDriverError::new(error,
-                                format!("{} {} did not match a known command", method, path)))
+    }
+
+    /// The methods that `path` can be requested with.
+    pub fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        let route_path = match self.route_path(path) {
+            Some(route_path) => route_path,
+            None => return vec![]
+        };
+        self.router.methods_for(route_path)
+    }
+
+    /// Encode a message as the request that decodes to it, giving the
+    /// method, the path including any base path, and the body.
+    ///
+    /// The path is built from the same route table as is used for decoding,
+    /// with the path parameters percent-encoded. The body is empty except
+    /// for `POST` requests. Extension commands can only be encoded if they
+    /// implement [`WebDriverExtensionCommand::http_route`].
+    ///
+    /// [`WebDriverExtensionCommand::http_route`]:
+    /// ../command/trait.WebDriverExtensionCommand.html#method.http_route
+    pub fn encode_request(&self, message: &WebDriverMessage<U>)
+                          -> WebDriverResult<(Method, String, String)> {
+        let (method, template, params) = match message.command {
+            WebDriverCommand::Extension(ref extension) => {
+                let (method, template, params) = try_opt!(
+                    extension.http_route(),
+                    ErrorStatus::UnsupportedOperation,
+                    "Extension command does not have an HTTP route");
+                let known = self.routes.iter().any(|&(ref x, ref path, ref route)| {
+                    *x == method && *path == template &&
+                        match *route { Route::Extension(_) => true, _ => false }
+                });
+                if !known {
+                    return Err(WebDriverError::new(
+                        ErrorStatus::UnsupportedOperation,
+                        format!("{} {} is not an extension route", method, template)));
+                }
+                (method, template, params)
+            },
+            ref command => {
+                let (route, params) = command_route(command);
+                let &(ref method, ref template, _) = try_opt!(
+                    self.routes.iter().find(|x| x.2 == route),
+                    ErrorStatus::UnknownError,
+                    "Command does not have a route");
+                let params = params.into_iter().map(|(x, y)| (x.to_string(), y)).collect();
+                (method.clone(), template.clone(), PathParams::new(params))
+            }
+        };
+
+        let mut path = self.prefix.clone();
+        for segment in template.split('/').skip(1) {
+            path.push('/');
+            if !(segment.starts_with('{') && segment.ends_with('}')) {
+                path.push_str(segment);
+                continue;
+            }
+            let name = segment[1..segment.len() - 1].split(':').next().unwrap_or("");
+            let value = if name == "sessionId" {
+                try_opt!(message.session_id.as_ref().map(|x| &x[..]),
+                         ErrorStatus::InvalidSessionId,
+                         format!("Missing session id for {}", template))
+            } else {
+                try_opt!(params.name(name),
+                         ErrorStatus::InvalidArgument,
+                         format!("Missing {} parameter for {}", name, template))
+            };
+            path.push_str(&percent_encode(value));
+        }
+
+        let body = if method == Post {
+            message.command.parameters_json()
+                .unwrap_or_else(|| Json::Object(BTreeMap::new()))
+                .to_string()
+        } else {
+            String::new()
+        };
+        Ok((method, path, body))
+    }
+
+    /// Decode a request, also returning the path template of the route that
+    /// matched it, if any.
+    ///
+    /// Trailing slashes on `path` are ignored.
+    pub fn decode_request_route(&self, method: Method, path: &str, body: &str)
+                                -> (Option<&str>, WebDriverResult<WebDriverMessage<U>>) {
+        match self.match_route(&method, path) {
+            Ok(matched) => {
+                (Some(matched.template),
+                 WebDriverMessage::from_http(matched.route.clone(),
+                                             &matched.params,
+                                             body,
+                                             method == Post))
+            },
+            Err(err) => (None, Err(err))
+        }
+    }
+}
+
+/// The route for a standard command, and the values of its path
+/// parameters other than `sessionId`.
+fn command_route<U: WebDriverExtensionRoute>(command: &WebDriverCommand<U::Command>)
+                                             -> (Route<U>, Vec<(&'static str, String)>) {
+    let element = |x: &WebElement| vec![("elementId", x.id.clone())];
+    match *command {
+        WebDriverCommand::NewSession(_) => (Route::NewSession, vec![]),
+        WebDriverCommand::DeleteSession => (Route::DeleteSession, vec![]),
+        WebDriverCommand::Get(_) => (Route::Get, vec![]),
+        WebDriverCommand::GetCurrentUrl => (Route::GetCurrentUrl, vec![]),
+        WebDriverCommand::GoBack => (Route::GoBack, vec![]),
+        WebDriverCommand::GoForward => (Route::GoForward, vec![]),
+        WebDriverCommand::Refresh => (Route::Refresh, vec![]),
+        WebDriverCommand::GetTitle => (Route::GetTitle, vec![]),
+        WebDriverCommand::GetPageSource => (Route::GetPageSource, vec![]),
+        WebDriverCommand::GetWindowHandle => (Route::GetWindowHandle, vec![]),
+        WebDriverCommand::GetWindowHandles => (Route::GetWindowHandles, vec![]),
+        WebDriverCommand::CloseWindow => (Route::CloseWindow, vec![]),
+        WebDriverCommand::GetWindowRect => (Route::GetWindowRect, vec![]),
+        WebDriverCommand::SetWindowRect(_) => (Route::SetWindowRect, vec![]),
+        WebDriverCommand::MaximizeWindow => (Route::MaximizeWindow, vec![]),
+        WebDriverCommand::SwitchToWindow(_) => (Route::SwitchToWindow, vec![]),
+        WebDriverCommand::SwitchToFrame(_) => (Route::SwitchToFrame, vec![]),
+        WebDriverCommand::SwitchToParentFrame => (Route::SwitchToParentFrame, vec![]),
+        WebDriverCommand::FindElement(_) => (Route::FindElement, vec![]),
+        WebDriverCommand::FindElements(_) => (Route::FindElements, vec![]),
+        WebDriverCommand::FindElementElement(ref x, _) => (Route::FindElementElement, element(x)),
+        WebDriverCommand::FindElementElements(ref x, _) => (Route::FindElementElements, element(x)),
+        WebDriverCommand::GetActiveElement => (Route::GetActiveElement, vec![]),
+        WebDriverCommand::IsDisplayed(ref x) => (Route::IsDisplayed, element(x)),
+        WebDriverCommand::IsSelected(ref x) => (Route::IsSelected, element(x)),
+        WebDriverCommand::GetElementAttribute(ref x, ref name) => {
+            (Route::GetElementAttribute, vec![("elementId", x.id.clone()), ("name", name.clone())])
+        },
+        WebDriverCommand::GetElementProperty(ref x, ref name) => {
+            (Route::GetElementProperty, vec![("elementId", x.id.clone()), ("name", name.clone())])
+        },
+        WebDriverCommand::GetCSSValue(ref x, ref name) => {
+            (Route::GetCSSValue, vec![("elementId", x.id.clone()), ("propertyName", name.clone())])
+        },
+        WebDriverCommand::GetElementText(ref x) => (Route::GetElementText, element(x)),
+        WebDriverCommand::GetElementTagName(ref x) => (Route::GetElementTagName, element(x)),
+        WebDriverCommand::GetElementRect(ref x) => (Route::GetElementRect, element(x)),
+        WebDriverCommand::IsEnabled(ref x) => (Route::IsEnabled, element(x)),
+        WebDriverCommand::ExecuteScript(_) => (Route::ExecuteScript, vec![]),
+        WebDriverCommand::ExecuteAsyncScript(_) => (Route::ExecuteAsyncScript, vec![]),
+        WebDriverCommand::GetCookies => (Route::GetCookies, vec![]),
+        WebDriverCommand::GetNamedCookie(ref name) => {
+            (Route::GetNamedCookie, vec![("name", name.clone())])
+        },
+        WebDriverCommand::AddCookie(_) => (Route::AddCookie, vec![]),
+        WebDriverCommand::DeleteCookies => (Route::DeleteCookies, vec![]),
+        WebDriverCommand::DeleteCookie(ref name) => {
+            (Route::DeleteCookie, vec![("name", name.clone())])
+        },
+        WebDriverCommand::GetTimeouts => (Route::GetTimeouts, vec![]),
+        WebDriverCommand::SetTimeouts(_) => (Route::SetTimeouts, vec![]),
+        WebDriverCommand::ElementClick(ref x) => (Route::ElementClick, element(x)),
+        WebDriverCommand::ElementTap(ref x) => (Route::ElementTap, element(x)),
+        WebDriverCommand::ElementClear(ref x) => (Route::ElementClear, element(x)),
+        WebDriverCommand::ElementSendKeys(ref x, _) => (Route::ElementSendKeys, element(x)),
+        WebDriverCommand::PerformActions(_) => (Route::PerformActions, vec![]),
+        WebDriverCommand::ReleaseActions => (Route::ReleaseActions, vec![]),
+        WebDriverCommand::DismissAlert => (Route::DismissAlert, vec![]),
+        WebDriverCommand::AcceptAlert => (Route::AcceptAlert, vec![]),
+        WebDriverCommand::GetAlertText => (Route::GetAlertText, vec![]),
+        WebDriverCommand::SendAlertText(_) => (Route::SendAlertText, vec![]),
+        WebDriverCommand::TakeScreenshot => (Route::TakeScreenshot, vec![]),
+        WebDriverCommand::TakeElementScreenshot(ref x) => (Route::TakeElementScreenshot, element(x)),
+        WebDriverCommand::Status => (Route::Status, vec![]),
+        WebDriverCommand::Extension(_) => unreachable!(),
+    }
+}
+
+/// Decode a percent-encoded path segment.
+///
+/// Unlike a browser, this doesn't pass malformed escapes through unchanged:
+/// a `%` that isn't followed by two hex digits, or escapes that don't decode
+/// to UTF-8, are an `InvalidArgument` error.
+pub fn percent_decode(value: &str) -> WebDriverResult<String> {
+    let invalid = || WebDriverError::new(ErrorStatus::InvalidArgument,
+                                         format!("Invalid percent-encoding in {}", value));
+    let malformed = value.split('%').skip(1).any(|escape| {
+        escape.len() < 2 || !escape.as_bytes()[..2].iter().all(|x| (*x as char).is_digit(16))
+    });
+    if malformed {
+        return Err(invalid());
+    }
+    percent_encoding::percent_decode(value.as_bytes())
+        .decode_utf8()
+        .map(|x| x.into_owned())
+        .map_err(|_| invalid())
+}
+
+/// Everything but the unreserved characters of RFC 3986.
+#[derive(Clone, Copy)]
+struct PathParamEncodeSet;
+
+impl EncodeSet for PathParamEncodeSet {
+    fn contains(&self, byte: u8) -> bool {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => false,
+            _ => true
+        }
+    }
+}
+
+/// Percent-encode a value for use as a path segment.
+///
+/// Everything but the unreserved characters of RFC 3986 is encoded, so the
+/// value round-trips through [`percent_decode`].
+///
+/// [`percent_decode`]: fn.percent_decode.html
+pub fn percent_encode(value: &str) -> String {
+    percent_encoding::utf8_percent_encode(value, PathParamEncodeSet).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::method::Method::{Delete, Get, Post};
+
+    use hyper::method::Method;
+    use rustc_serialize::json::Json;
+
+    use command::{GetParameters, JavascriptCommandParameters, LocatorParameters, SendKeysParameters,
+                  SwitchToFrameParameters, SwitchToWindowParameters, TimeoutsParameters,
+                  VoidWebDriverExtensionCommand, WebDriverCommand, WebDriverExtensionCommand,
+                  WebDriverMessage, WindowRectParameters};
+    use common::{FrameId, LocatorStrategy, Nullable, WebElement};
+    use error::{ErrorStatus, WebDriverResult};
+    use super::{percent_decode, percent_encode, PathParams, VoidWebDriverExtensionRoute,
+                WebDriverExtensionRoute, WebDriverHttpApi};
+
+    fn api() -> WebDriverHttpApi<VoidWebDriverExtensionRoute> {
+        WebDriverHttpApi::new(&[])
+    }
+
+    #[test]
+    fn test_prefix() {
+        let api = api().with_prefix("/wd/hub/");
+
+        let (route, msg) = api.decode_request_route(Get, "/wd/hub/session/1/url", "");
+        assert_eq!(route, Some("/session/{sessionId}/url"));
+        let msg = msg.unwrap();
+        assert_eq!(msg.session_id, Some("1".to_string()));
+        assert!(match msg.command { WebDriverCommand::GetCurrentUrl => true, _ => false });
+
+        assert!(api.decode_request(Get, "/wd/hub/status", "").is_ok());
+        for path in &["/session/1/url", "/wd/hubsession/1/url", "/wd/session/1/url"] {
+            let err = api.decode_request(Get, path, "").err().unwrap();
+            assert_eq!(err.error, ErrorStatus::UnknownPath);
+        }
+    }
+
+    #[test]
+    fn test_prefix_without_leading_slash() {
+        let api = api().with_prefix("wd/hub");
+        assert!(api.decode_request(Get, "/wd/hub/status", "").is_ok());
+    }
+
+    #[test]
+    fn test_trailing_slash() {
+        let api = api();
+        assert!(api.decode_request(Get, "/status/", "").is_ok());
+        assert!(api.decode_request(Post, "/session/", "{\"capabilities\": {}}").is_ok());
+        assert!(api.decode_request(Get, "/session/1/url//", "").is_ok());
+
+        let api = api.with_prefix("/wd/hub");
+        assert!(api.decode_request(Get, "/wd/hub/session/1/url/", "").is_ok());
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("plain").unwrap(), "plain");
+        assert_eq!(percent_decode("a%20b%2fc%2F").unwrap(), "a b/c/");
+        assert_eq!(percent_decode("%C3%A9t%C3%A9").unwrap(), "\u{e9}t\u{e9}");
+        for value in &["%", "%2", "%zz", "a%2g", "%ff", "%C3"] {
+            assert_eq!(percent_decode(value).err().unwrap().error, ErrorStatus::InvalidArgument);
+        }
+    }
+
+    #[test]
+    fn test_encoded_cookie_name() {
+        let api = api();
+        let msg = api.decode_request(Get, "/session/1/cookie/a%20b", "").unwrap();
+        match msg.command {
+            WebDriverCommand::GetNamedCookie(ref name) => assert_eq!(name, "a b"),
+            _ => panic!("Expected GetNamedCookie"),
+        }
+        let msg = api.decode_request(Delete, "/session/1/cookie/foo%2Fbar", "").unwrap();
+        match msg.command {
+            WebDriverCommand::DeleteCookie(ref name) => assert_eq!(name, "foo/bar"),
+            _ => panic!("Expected DeleteCookie"),
+        }
+
+        let err = api.decode_request(Get, "/session/1/cookie/a%2", "").err().unwrap();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+    }
+
+    #[test]
+    fn test_encoded_attribute_name() {
+        let api = api();
+        let msg = api.decode_request(Get, "/session/1/element/e%201/attribute/data%3Avalue", "")
+            .unwrap();
+        match msg.command {
+            WebDriverCommand::GetElementAttribute(ref element, ref name) => {
+                assert_eq!(element.id, "e 1");
+                assert_eq!(name, "data:value");
+            },
+            _ => panic!("Expected GetElementAttribute"),
+        }
+        let msg = api.decode_request(Get, "/session/1/element/e/css/font%2Dfamily", "").unwrap();
+        match msg.command {
+            WebDriverCommand::GetCSSValue(_, ref name) => assert_eq!(name, "font-family"),
+            _ => panic!("Expected GetCSSValue"),
+        }
+
+        let err = api.decode_request(Get, "/session/1/element/e/attribute/%E9", "").err().unwrap();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+    }
+
+    #[test]
+    fn test_method_not_allowed() {
+        let api = api();
+        let err = api.decode_request(Post, "/session/1/cookie/a", "{}").err().unwrap();
+        assert_eq!(err.error, ErrorStatus::UnknownMethod);
+        assert_eq!(api.allowed_methods("/session/1/cookie/a"), vec![Get, Delete]);
+        assert_eq!(api.allowed_methods("/session/1/cookie/a/"), vec![Get, Delete]);
+        assert_eq!(api.allowed_methods("/session/1/unknown"), vec![]);
+
+        let err = api.decode_request(Get, "/session/1/unknown", "").err().unwrap();
+        assert_eq!(err.error, ErrorStatus::UnknownPath);
+    }
+
+    #[test]
+    fn test_extension_route_conflicts() {
+        let route = VoidWebDriverExtensionRoute;
+        assert!(WebDriverHttpApi::try_new(&[(Get, "/session/{sessionId}/moz/context", route.clone()),
+                                            (Post, "/session/{sessionId}/moz/context", route.clone())])
+                .is_ok());
+        assert!(WebDriverHttpApi::try_new(&[(Get, "/session/{id}/url", route.clone())]).is_err());
+        assert!(WebDriverHttpApi::try_new(&[(Get, "/session/{sessionId}/moz/context", route.clone()),
+                                            (Get, "/session/{sessionId}/moz/context", route.clone())])
+                .is_err());
+        assert!(WebDriverHttpApi::try_new(&[(Get, "/session/{sessionId/moz", route.clone())]).is_err());
+
+        // `new` leaves the conflicting route out
+        let api = WebDriverHttpApi::new(&[(Get, "/session/{id}/url", route.clone())]);
+        match api.decode_request(Get, "/session/1/url", "").unwrap().command {
+            WebDriverCommand::GetCurrentUrl => {},
+            _ => panic!("Expected GetCurrentUrl"),
+        }
+    }
+
+    fn message(session_id: Option<&str>, command: WebDriverCommand<VoidWebDriverExtensionCommand>)
+               -> WebDriverMessage {
+        WebDriverMessage::new(session_id.map(|x| x.to_string()), command)
+    }
+
+    #[test]
+    fn test_encode_request() {
+        let api = api().with_prefix("/wd/hub");
+        let element = WebElement::new("e/1".into());
+        let new_session = api.decode_request(Post, "/wd/hub/session",
+                                             r#"{"capabilities": {"alwaysMatch": {}}}"#)
+            .unwrap();
+        let messages = vec![
+            new_session,
+            message(Some("1"), WebDriverCommand::Get(GetParameters {
+                url: "http://example.test/".into()
+            })),
+            message(Some("1"), WebDriverCommand::GoBack),
+            message(Some("1"), WebDriverCommand::GetWindowRect),
+            message(Some("a b"), WebDriverCommand::FindElementElement(element.clone(), LocatorParameters {
+                using: LocatorStrategy::CSSSelector,
+                value: "p > a".into(),
+            })),
+            message(Some("1"), WebDriverCommand::GetElementAttribute(element.clone(),
+                                                                     "data:x".into())),
+            message(Some("1"), WebDriverCommand::GetCSSValue(element.clone(), "font-family".into())),
+            message(Some("1"), WebDriverCommand::ElementSendKeys(element.clone(), SendKeysParameters {
+                text: "\u{e9}t\u{e9}".into()
+            })),
+            message(Some("1"), WebDriverCommand::DeleteCookie("a%b".into())),
+            message(Some("1"), WebDriverCommand::ExecuteScript(JavascriptCommandParameters {
+                script: "return arguments[0];".into(),
+                args: Nullable::Value(vec![Json::U64(1)]),
+            })),
+            message(Some("1"), WebDriverCommand::SetTimeouts(TimeoutsParameters {
+                script: Some(1),
+                page_load: None,
+                implicit: Some(3),
+            })),
+            message(Some("1"), WebDriverCommand::SwitchToWindow(SwitchToWindowParameters {
+                handle: "w1".into()
+            })),
+            message(Some("1"), WebDriverCommand::SwitchToFrame(SwitchToFrameParameters {
+                id: FrameId::Short(2)
+            })),
+            message(Some("1"), WebDriverCommand::SetWindowRect(WindowRectParameters {
+                x: Nullable::Value(1),
+                y: Nullable::Null,
+                width: Nullable::Value(800),
+                height: Nullable::Value(600),
+            })),
+            message(None, WebDriverCommand::Status),
+        ];
+        for message in messages {
+            let (method, path, body) = api.encode_request(&message).unwrap();
+            assert!(path.starts_with("/wd/hub/"));
+            assert_eq!(body.is_empty(), method != Post);
+            let decoded = api.decode_request(method, &path, &body).unwrap();
+            assert!(decoded == message, "{} did not round-trip", path);
+        }
+
+        let (method, path, body) = api.encode_request(
+            &message(Some("a/b"), WebDriverCommand::GetElementAttribute(element, "x y".into())))
+            .unwrap();
+        assert_eq!(method, Get);
+        assert_eq!(path, "/wd/hub/session/a%2Fb/element/e%2F1/attribute/x%20y");
+        assert_eq!(body, "");
+        let (method, path, body) = api.encode_request(&message(Some("1"), WebDriverCommand::GoBack))
+            .unwrap();
+        assert_eq!((method, &path[..], &body[..]), (Post, "/wd/hub/session/1/back", "{}"));
+
+        let err = api.encode_request(&message(None, WebDriverCommand::GetTitle)).err().unwrap();
+        assert_eq!(err.error, ErrorStatus::InvalidSessionId);
+    }
+
+    #[test]
+    fn test_percent_encode() {
+        assert_eq!(percent_encode("plain-value_1.~"), "plain-value_1.~");
+        assert_eq!(percent_encode("a b/c%"), "a%20b%2Fc%25");
+        assert_eq!(percent_encode("\u{e9}"), "%C3%A9");
+        assert_eq!(percent_decode(&percent_encode("?#[]@!$&'()*+,;=")).unwrap(),
+                   "?#[]@!$&'()*+,;=");
+    }
+
+    #[derive(Clone, PartialEq)]
+    enum ContextRoute {
+        Get,
+        Set,
+    }
+
+    #[derive(Clone, PartialEq)]
+    enum ContextCommand {
+        Get,
+        Set(String),
+    }
+
+    impl WebDriverExtensionCommand for ContextCommand {
+        fn parameters_json(&self) -> Option<Json> {
+            match *self {
+                ContextCommand::Get => None,
+                ContextCommand::Set(ref x) => Json::from_str(&format!("{{\"context\": \"{}\"}}", x)).ok(),
+            }
+        }
+
+        fn http_route(&self) -> Option<(Method, String, PathParams)> {
+            match *self {
+                ContextCommand::Get => {
+                    Some((Get, "/session/{sessionId}/moz/context".into(), PathParams::default()))
+                },
+                ContextCommand::Set(_) => None,
+            }
+        }
+    }
+
+    impl WebDriverExtensionRoute for ContextRoute {
+        type Command = ContextCommand;
+
+        fn command(&self, _: &PathParams, body: &Json) -> WebDriverResult<WebDriverCommand<ContextCommand>> {
+            Ok(WebDriverCommand::Extension(match *self {
+                ContextRoute::Get => ContextCommand::Get,
+                ContextRoute::Set => {
+                    ContextCommand::Set(body.find("context").and_then(|x| x.as_string())
+                                        .unwrap_or("").into())
+                }
+            }))
+        }
+    }
+
+    #[test]
+    fn test_encode_extension_request() {
+        let api = WebDriverHttpApi::new(&[(Get, "/session/{sessionId}/moz/context", ContextRoute::Get),
+                                          (Post, "/session/{sessionId}/moz/context", ContextRoute::Set)]);
+        let message = WebDriverMessage::<ContextRoute>::new(
+            Some("1".into()), WebDriverCommand::Extension(ContextCommand::Get));
+        let (method, path, body) = api.encode_request(&message).unwrap();
+        assert_eq!((method.clone(), &path[..], &body[..]), (Get, "/session/1/moz/context", ""));
+        assert!(api.decode_request(method, &path, &body).unwrap() == message);
+
+        let message = WebDriverMessage::<ContextRoute>::new(
+            Some("1".into()), WebDriverCommand::Extension(ContextCommand::Set("chrome".into())));
+        let err = api.encode_request(&message).err().unwrap();
+        assert_eq!(err.error, ErrorStatus::UnsupportedOperation);
+
+        // The route has to be one the API was created with.
+        let api = WebDriverHttpApi::<ContextRoute>::new(&[]);
+        let message = WebDriverMessage::<ContextRoute>::new(
+            Some("1".into()), WebDriverCommand::Extension(ContextCommand::Get));
+        let err = api.encode_request(&message).err().unwrap();
+        assert_eq!(err.error, ErrorStatus::UnsupportedOperation);
+    }
+
+    #[test]
+    fn test_path_params() {
+        let api = api();
+        let matched = api.match_route(&Get, "/session/a%2Fb/element/e/attribute/x").ok().unwrap();
+        assert_eq!(matched.template, "/session/{sessionId}/element/{elementId}/attribute/{name}");
+        assert_eq!(matched.params.name("sessionId"), Some("a/b"));
+        assert_eq!(matched.params.get::<String>("name").unwrap(), "x");
+        assert_eq!(matched.params.get::<u64>("name").err().unwrap().error,
+                   ErrorStatus::InvalidArgument);
+        assert!(matched.params.name("propertyName").is_none());
     }
 }
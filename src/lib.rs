@@ -1,23 +1,35 @@
 #![allow(non_snake_case)]
 
 extern crate backtrace;
+extern crate flate2;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "tls")]
+extern crate openssl;
 extern crate rustc_serialize;
 extern crate hyper;
-extern crate regex;
 extern crate cookie;
 extern crate time;
 extern crate url;
 
 #[macro_use] pub mod macros;
+pub mod actions;
 pub mod httpapi;
 pub mod capabilities;
+pub mod client;
 pub mod command;
 pub mod common;
+pub mod dispatch;
 pub mod error;
 pub mod server;
 pub mod response;
+pub mod wait;
+mod metrics;
+mod router;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(unix)]
+mod unix;
 
 #[cfg(test)]
 mod nullable_tests {
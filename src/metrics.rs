@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hyper::method::Method;
+
+use error::ErrorStatus;
+
+/// Upper bounds, in seconds, of the request duration histogram buckets.
+static BUCKETS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0, 60.0, 300.0];
+
+/// Label used for requests that did not match any route.
+static UNMATCHED_ROUTE: &'static str = "unmatched";
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; 12],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(BUCKETS.iter()) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct MetricsData {
+    requests: BTreeMap<(String, String), u64>,
+    errors: BTreeMap<&'static str, u64>,
+    durations: BTreeMap<(String, String), Histogram>,
+}
+
+/// Request metrics collected by the server, rendered in the Prometheus text
+/// exposition format.
+pub struct Metrics {
+    data: Mutex<MetricsData>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            data: Mutex::new(MetricsData::default()),
+        }
+    }
+
+    /// Record a handled request.
+    ///
+    /// `route` is the path template of the matched route, and `error` the
+    /// status of the error returned to the client, if any.
+    pub fn record(&self,
+                  method: &Method,
+                  route: Option<&str>,
+                  error: Option<&ErrorStatus>,
+                  duration: Duration) {
+        let route = route.unwrap_or(UNMATCHED_ROUTE);
+        let seconds = duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9;
+        let mut data = match self.data.lock() {
+            Ok(data) => data,
+            Err(_) => return
+        };
+        let key = (method.to_string(), route.to_string());
+        *data.requests.entry(key.clone()).or_insert(0) += 1;
+        if let Some(error) = error {
+            *data.errors.entry(error.error_code()).or_insert(0) += 1;
+        }
+        data.durations.entry(key).or_insert_with(Histogram::default).observe(seconds);
+    }
+
+    pub fn render(&self, active_sessions: usize) -> String {
+        let mut out = String::new();
+        let data = match self.data.lock() {
+            Ok(data) => data,
+            Err(_) => return out
+        };
+
+        out.push_str("# HELP webdriver_requests_total Number of requests handled, by route.\n");
+        out.push_str("# TYPE webdriver_requests_total counter\n");
+        for (&(ref method, ref route), count) in data.requests.iter() {
+            writeln!(out, "webdriver_requests_total{{method=\"{}\",route=\"{}\"}} {}",
+                     escape(method), escape(route), count).unwrap();
+        }
+
+        out.push_str("# HELP webdriver_errors_total Number of error responses, by error code.\n");
+        out.push_str("# TYPE webdriver_errors_total counter\n");
+        for (error, count) in data.errors.iter() {
+            writeln!(out, "webdriver_errors_total{{error=\"{}\"}} {}", escape(error), count).unwrap();
+        }
+
+        out.push_str("# HELP webdriver_request_duration_seconds Time taken to handle requests, by route.\n");
+        out.push_str("# TYPE webdriver_request_duration_seconds histogram\n");
+        for (&(ref method, ref route), histogram) in data.durations.iter() {
+            let labels = format!("method=\"{}\",route=\"{}\"", escape(method), escape(route));
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+                writeln!(out, "webdriver_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                         labels, bound, count).unwrap();
+            }
+            writeln!(out, "webdriver_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                     labels, histogram.count).unwrap();
+            writeln!(out, "webdriver_request_duration_seconds_sum{{{}}} {}",
+                     labels, histogram.sum).unwrap();
+            writeln!(out, "webdriver_request_duration_seconds_count{{{}}} {}",
+                     labels, histogram.count).unwrap();
+        }
+
+        out.push_str("# HELP webdriver_active_sessions Number of sessions currently running.\n");
+        out.push_str("# TYPE webdriver_active_sessions gauge\n");
+        writeln!(out, "webdriver_active_sessions {}", active_sessions).unwrap();
+        out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use hyper::method::Method;
+
+    use error::ErrorStatus;
+    use super::Metrics;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.record(&Method::Get, Some("/session/{sessionId}/url"), None,
+                       Duration::from_millis(20));
+        metrics.record(&Method::Get, Some("/session/{sessionId}/url"),
+                       Some(&ErrorStatus::NoSuchWindow), Duration::from_millis(200));
+        metrics.record(&Method::Post, None, Some(&ErrorStatus::UnknownPath),
+                       Duration::from_millis(1));
+
+        let text = metrics.render(1);
+        let lines = text.lines().collect::<Vec<_>>();
+        assert!(lines.contains(
+            &r#"webdriver_requests_total{method="GET",route="/session/{sessionId}/url"} 2"#));
+        assert!(lines.contains(&r#"webdriver_requests_total{method="POST",route="unmatched"} 1"#));
+        assert!(lines.contains(&r#"webdriver_errors_total{error="no such window"} 1"#));
+        assert!(lines.contains(&r#"webdriver_errors_total{error="unknown command"} 1"#));
+        assert!(lines.contains(
+            &r#"webdriver_request_duration_seconds_bucket{method="GET",route="/session/{sessionId}/url",le="0.025"} 1"#));
+        assert!(lines.contains(
+            &r#"webdriver_request_duration_seconds_bucket{method="GET",route="/session/{sessionId}/url",le="0.25"} 2"#));
+        assert!(lines.contains(
+            &r#"webdriver_request_duration_seconds_count{method="GET",route="/session/{sessionId}/url"} 2"#));
+        assert!(lines.contains(
+            &r#"webdriver_request_duration_seconds_count{method="POST",route="unmatched"} 1"#));
+        assert!(lines.contains(&"webdriver_active_sessions 1"));
+    }
+}
@@ -1,7 +1,14 @@
+use rustc_serialize::Encodable;
 use rustc_serialize::json::{self, Json, ToJson};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
 
+use command::{AddCookieParameters, Parameters, WebDriverCommand, WebDriverExtensionCommand};
 use common::{Nullable, Date};
+use error::{ErrorStatus, WebDriverError, WebDriverResult};
 use cookie;
+use hyper::status::StatusCode;
 use time;
 
 #[derive(Debug)]
@@ -19,29 +26,179 @@ pub enum WebDriverResponse {
 
 impl WebDriverResponse {
     pub fn to_json_string(self) -> String {
-        let obj = match self {
-            WebDriverResponse::CloseWindow(ref x) => json::encode(&x.to_json()),
-            WebDriverResponse::Cookie(ref x) => json::encode(x),
-            WebDriverResponse::DeleteSession => Ok("{}".to_string()),
-            WebDriverResponse::ElementRect(ref x) => json::encode(x),
-            WebDriverResponse::Generic(ref x) => json::encode(x),
-            WebDriverResponse::NewSession(ref x) => json::encode(x),
-            WebDriverResponse::Timeouts(ref x) => json::encode(x),
-            WebDriverResponse::Void => Ok("{}".to_string()),
-            WebDriverResponse::WindowRect(ref x) => json::encode(x),
-        }.unwrap();
-
-        match self {
+        let mut data = vec![];
+        self.write_json(&mut data).unwrap();
+        String::from_utf8(data).unwrap()
+    }
+
+    /// Write the JSON encoding of the response, including the `value`
+    /// wrapper, to `writer` as it is produced.
+    ///
+    /// Unlike `to_json_string` this doesn't hold the whole encoded
+    /// response in memory, which matters for large values such as
+    /// screenshots.
+    pub fn write_json<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = FmtWriter {
+            inner: writer,
+            error: None,
+        };
+        let wrapped = match *self {
             WebDriverResponse::Generic(_) |
-            WebDriverResponse::Cookie(_) => obj,
-            _ => {
-                let mut data = String::with_capacity(11 + obj.len());
-                data.push_str("{\"value\": ");
-                data.push_str(&*obj);
-                data.push_str("}");
-                data
+            WebDriverResponse::Cookie(_) => false,
+            _ => true
+        };
+        if wrapped {
+            writer.inner.write_all(b"{\"value\": ")?;
+        }
+        let result = {
+            let mut encoder = json::Encoder::new(&mut writer);
+            match *self {
+                WebDriverResponse::CloseWindow(ref x) => x.to_json().encode(&mut encoder),
+                WebDriverResponse::Cookie(ref x) => x.encode(&mut encoder),
+                WebDriverResponse::DeleteSession => Json::Object(BTreeMap::new()).encode(&mut encoder),
+                WebDriverResponse::ElementRect(ref x) => x.encode(&mut encoder),
+                WebDriverResponse::Generic(ref x) => x.encode(&mut encoder),
+                WebDriverResponse::NewSession(ref x) => x.encode(&mut encoder),
+                WebDriverResponse::Timeouts(ref x) => x.encode(&mut encoder),
+                WebDriverResponse::Void => Json::Object(BTreeMap::new()).encode(&mut encoder),
+                WebDriverResponse::WindowRect(ref x) => x.encode(&mut encoder),
             }
+        };
+        if let Err(err) = result {
+            return Err(writer.error.take().unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+            }));
         }
+        if wrapped {
+            writer.inner.write_all(b"}")?;
+        }
+        Ok(())
+    }
+
+    /// Decode the body of the response to `command`, which had the HTTP
+    /// status `status`.
+    ///
+    /// The type of response is determined by the command, as the body
+    /// alone is ambiguous. Commands without a specific response type give
+    /// `Generic` responses, or `Void` if their value is empty. A response
+    /// with an error status is returned as the `Err` it describes, so a
+    /// successful value that happens to have an `error` key is not mistaken
+    /// for one.
+    pub fn from_json<T: WebDriverExtensionCommand>(command: &WebDriverCommand<T>,
+                                                   status: StatusCode,
+                                                   body: &Json)
+                                                   -> WebDriverResult<WebDriverResponse> {
+        if !status.is_success() {
+            return Err(WebDriverError::from_json(body).unwrap_or_else(|_| {
+                WebDriverError::new(ErrorStatus::UnknownError,
+                                    format!("Error response with status {}: {}", status, body))
+            }));
+        }
+        let value = try_opt!(body.find("value"),
+                             ErrorStatus::UnknownError,
+                             "Response did not have a value");
+        let response = match *command {
+            WebDriverCommand::NewSession(_) => {
+                // Older remote ends put the session id next to the value,
+                // which then holds the capabilities.
+                let (session_id, capabilities) = match value.find("sessionId") {
+                    Some(session_id) => {
+                        (session_id, value.find("capabilities").cloned().unwrap_or(Json::Null))
+                    },
+                    None => {
+                        (try_opt!(body.find("sessionId"),
+                                  ErrorStatus::UnknownError,
+                                  "New session response did not have a session id"),
+                         value.clone())
+                    }
+                };
+                let session_id = try_opt!(session_id.as_string(),
+                                          ErrorStatus::UnknownError,
+                                          "Session id was not a string");
+                WebDriverResponse::NewSession(NewSessionResponse::new(session_id.to_string(),
+                                                                      capabilities))
+            },
+            WebDriverCommand::DeleteSession => WebDriverResponse::DeleteSession,
+            WebDriverCommand::CloseWindow => {
+                let handles = try_opt!(value.as_array(),
+                                       ErrorStatus::UnknownError,
+                                       "Window handles were not an array");
+                let handles = handles.iter()
+                    .map(|x| x.as_string().map(|x| x.to_string()))
+                    .collect::<Option<Vec<_>>>();
+                WebDriverResponse::CloseWindow(CloseWindowResponse::new(
+                    try_opt!(handles, ErrorStatus::UnknownError, "Window handle was not a string")))
+            },
+            WebDriverCommand::GetCookies |
+            WebDriverCommand::GetNamedCookie(_) => {
+                let cookies = match *value {
+                    Json::Array(ref cookies) => {
+                        cookies.iter().map(cookie_from_json).collect::<WebDriverResult<_>>()?
+                    },
+                    ref cookie => vec![cookie_from_json(cookie)?]
+                };
+                WebDriverResponse::Cookie(CookieResponse::new(cookies))
+            },
+            WebDriverCommand::GetElementRect(_) => {
+                WebDriverResponse::ElementRect(ElementRectResponse::new(
+                    field(value, "x", Json::as_f64)?,
+                    field(value, "y", Json::as_f64)?,
+                    field(value, "width", Json::as_f64)?,
+                    field(value, "height", Json::as_f64)?))
+            },
+            WebDriverCommand::GetWindowRect |
+            WebDriverCommand::SetWindowRect(_) |
+            WebDriverCommand::MaximizeWindow => {
+                WebDriverResponse::WindowRect(WindowRectResponse {
+                    x: field(value, "x", Json::as_i64)?,
+                    y: field(value, "y", Json::as_i64)?,
+                    width: field(value, "width", Json::as_u64)?,
+                    height: field(value, "height", Json::as_u64)?,
+                })
+            },
+            WebDriverCommand::GetTimeouts => {
+                WebDriverResponse::Timeouts(TimeoutsResponse::new(
+                    field(value, "script", Json::as_u64)?,
+                    field(value, "pageLoad", Json::as_u64)?,
+                    field(value, "implicit", Json::as_u64)?))
+            },
+            _ => match *value {
+                Json::Null => WebDriverResponse::Void,
+                Json::Object(ref x) if x.is_empty() => WebDriverResponse::Void,
+                ref value => WebDriverResponse::Generic(ValueResponse::new(value.clone())),
+            }
+        };
+        Ok(response)
+    }
+}
+
+fn field<T, F: Fn(&Json) -> Option<T>>(value: &Json, name: &str, convert: F) -> WebDriverResult<T> {
+    Ok(try_opt!(value.find(name).and_then(convert),
+                ErrorStatus::UnknownError,
+                format!("Response did not have a valid {} field", name)))
+}
+
+fn cookie_from_json(value: &Json) -> WebDriverResult<Cookie> {
+    let mut wrapper = BTreeMap::new();
+    wrapper.insert("cookie".to_string(), value.clone());
+    let parameters = AddCookieParameters::from_json(&Json::Object(wrapper))?;
+    Ok(Cookie::new(parameters.name, parameters.value, parameters.path, parameters.domain,
+                   parameters.expiry, parameters.secure, parameters.httpOnly))
+}
+
+/// Adapts an `io::Write` to the `fmt::Write` that the JSON encoder writes
+/// to, keeping hold of any I/O error.
+struct FmtWriter<W: Write> {
+    inner: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> fmt::Write for FmtWriter<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.inner.write_all(s.as_bytes()).map_err(|err| {
+            self.error = Some(err);
+            fmt::Error
+        })
     }
 }
 
@@ -204,6 +361,13 @@ impl CookieResponse {
 #[cfg(test)]
 mod tests {
     use std::collections::BTreeMap;
+    use std::io;
+
+    use capabilities::SpecNewSessionParameters;
+    use command::{NewSessionParameters, VoidWebDriverExtensionCommand, WebDriverCommand};
+    use common::{Date, WebElement};
+    use error::{ErrorStatus, WebDriverResult};
+    use hyper::status::StatusCode;
     use rustc_serialize::json::Json;
     use super::{WebDriverResponse,
                 CloseWindowResponse,
@@ -285,6 +449,118 @@ mod tests {
         test(resp, expected);
     }
 
+    #[test]
+    fn test_write_json() {
+        let value = Json::String("a\"b".repeat(1000));
+        let resp = WebDriverResponse::Generic(ValueResponse::new(value.clone()));
+        let mut data = vec![];
+        resp.write_json(&mut data).unwrap();
+        assert_eq!(String::from_utf8(data).unwrap(), resp.to_json_string());
+
+        let resp = WebDriverResponse::Timeouts(TimeoutsResponse::new(1, 2, 3));
+        let mut data = vec![];
+        resp.write_json(&mut data).unwrap();
+        assert_eq!(&data[..], &br#"{"value": {"script":1,"pageLoad":2,"implicit":3}}"#[..]);
+    }
+
+    #[test]
+    fn test_write_json_error() {
+        let resp = WebDriverResponse::Generic(ValueResponse::new(Json::String("a".repeat(100))));
+        let mut buf = [0u8; 50];
+        let err = resp.write_json(&mut buf[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    }
+
+    fn decode(command: WebDriverCommand<VoidWebDriverExtensionCommand>, body: &str)
+              -> WebDriverResult<WebDriverResponse> {
+        WebDriverResponse::from_json(&command, StatusCode::Ok, &Json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn test_from_json() {
+        let element = WebElement::new("e".into());
+        let roundtrip = vec![
+            (WebDriverCommand::GetTitle,
+             WebDriverResponse::Generic(ValueResponse::new(Json::String("title".into())))),
+            (WebDriverCommand::CloseWindow,
+             WebDriverResponse::CloseWindow(CloseWindowResponse::new(vec!["w1".into()]))),
+            (WebDriverCommand::GetCookies,
+             WebDriverResponse::Cookie(CookieResponse::new(vec![
+                 Cookie::new("a".into(), "b".into(), Nullable::Value("/".into()), Nullable::Null,
+                             Nullable::Value(Date::new(10)), true, false)]))),
+            (WebDriverCommand::GetElementRect(element),
+             WebDriverResponse::ElementRect(ElementRectResponse::new(0.5, 1.0, 2.0, 3.0))),
+            (WebDriverCommand::GetWindowRect,
+             WebDriverResponse::WindowRect(WindowRectResponse { x: -1, y: 1, width: 2, height: 3 })),
+            (WebDriverCommand::GetTimeouts,
+             WebDriverResponse::Timeouts(TimeoutsResponse::new(1, 2, 3))),
+            (WebDriverCommand::DeleteSession, WebDriverResponse::DeleteSession),
+            (WebDriverCommand::GoBack, WebDriverResponse::Void),
+        ];
+        for (command, response) in roundtrip {
+            let expected = format!("{:?}", response);
+            let decoded = decode(command, &response.to_json_string()).unwrap();
+            assert_eq!(format!("{:?}", decoded), expected);
+        }
+
+        let decoded = decode(WebDriverCommand::GoBack, r#"{"value": null}"#).unwrap();
+        assert!(match decoded { WebDriverResponse::Void => true, _ => false });
+        let decoded = decode(WebDriverCommand::GetNamedCookie("a".into()),
+                             r#"{"value": {"name": "a", "value": "b"}}"#).unwrap();
+        match decoded {
+            WebDriverResponse::Cookie(ref x) => {
+                assert_eq!(x.value, vec![Cookie::new("a".into(), "b".into(), Nullable::Null,
+                                                     Nullable::Null, Nullable::Null, false, false)]);
+            },
+            _ => panic!("Expected a cookie response"),
+        }
+        assert!(decode(WebDriverCommand::GetWindowRect, r#"{"value": {"x": 1}}"#).is_err());
+        assert!(decode(WebDriverCommand::GetTitle, r#"{"title": "x"}"#).is_err());
+    }
+
+    #[test]
+    fn test_new_session_from_json() {
+        let command = WebDriverCommand::NewSession(NewSessionParameters::Spec(
+            SpecNewSessionParameters { alwaysMatch: BTreeMap::new(), firstMatch: vec![] }));
+        let body = r#"{"value": {"sessionId": "1", "capabilities": {"browserName": "x"}}}"#;
+        let legacy = r#"{"sessionId": "1", "status": 0, "value": {"browserName": "x"}}"#;
+        for body in &[body, legacy] {
+            match decode(command.clone(), body).unwrap() {
+                WebDriverResponse::NewSession(ref x) => {
+                    assert_eq!(x.sessionId, "1");
+                    assert_eq!(x.capabilities.find("browserName").unwrap().as_string(), Some("x"));
+                },
+                _ => panic!("Expected a new session response"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_from_json() {
+        let decode_error = |status, body| {
+            WebDriverResponse::from_json(&WebDriverCommand::<VoidWebDriverExtensionCommand>::GetTitle,
+                                         status,
+                                         &Json::from_str(body).unwrap())
+        };
+        let body = r#"{"value": {"error": "no such element", "message": "Unable to locate #x",
+                                 "stacktrace": "remote stack"}}"#;
+        let err = decode_error(StatusCode::NotFound, body).err().unwrap();
+        assert_eq!(err.error, ErrorStatus::NoSuchElement);
+        assert_eq!(err.message, "Unable to locate #x");
+        assert_eq!(err.stacktrace(), Some("remote stack"));
+
+        let err = decode_error(StatusCode::InternalServerError, r#"{"value": null}"#).err().unwrap();
+        assert_eq!(err.error, ErrorStatus::UnknownError);
+
+        // A successful value may have an error key of its own
+        match decode(WebDriverCommand::GetTitle, body).unwrap() {
+            WebDriverResponse::Generic(ref x) => {
+                assert_eq!(x.value.find("error").unwrap().as_string(), Some("no such element"));
+            },
+            _ => panic!("Expected a generic response"),
+        }
+    }
+
     #[test]
     fn test_value() {
         let mut value = BTreeMap::new();
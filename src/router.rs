@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+
+use hyper::method::Method;
+
+use httpapi::percent_decode;
+
+/// Type of a path parameter, given in a route template as `{name:type}`.
+///
+/// Parameters without a type match any non-empty segment. The order of the
+/// variants is the order in which parameters are tried when several could
+/// match the same segment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum ParamType {
+    Int,
+    Uint,
+    String,
+}
+
+impl ParamType {
+    fn from_name(name: &str) -> Option<ParamType> {
+        match name {
+            "" | "string" => Some(ParamType::String),
+            "int" => Some(ParamType::Int),
+            "uint" => Some(ParamType::Uint),
+            _ => None
+        }
+    }
+
+    fn accepts(&self, segment: &str) -> bool {
+        match *self {
+            ParamType::String => !segment.is_empty(),
+            ParamType::Int => percent_decode(segment).ok().map_or(false, |x| x.parse::<i64>().is_ok()),
+            ParamType::Uint => percent_decode(segment).ok().map_or(false, |x| x.parse::<u64>().is_ok()),
+        }
+    }
+}
+
+enum Segment<'a> {
+    Static(&'a str),
+    Param(&'a str, ParamType),
+}
+
+fn parse_template(template: &str) -> Result<Vec<Segment<'_>>, String> {
+    if !template.starts_with('/') {
+        return Err(format!("Route {} does not start with /", template))
+    }
+    split_path(template).into_iter().map(|segment| {
+        if segment.starts_with('{') && segment.ends_with('}') {
+            let param = &segment[1..segment.len() - 1];
+            let (name, type_name) = match param.find(':') {
+                Some(index) => (&param[..index], &param[index + 1..]),
+                None => (param, "")
+            };
+            match ParamType::from_name(type_name) {
+                Some(param_type) if !name.is_empty() => Ok(Segment::Param(name, param_type)),
+                _ => Err(format!("Invalid parameter {} in route {}", segment, template))
+            }
+        } else if segment.contains('{') || segment.contains('}') {
+            Err(format!("Invalid segment {} in route {}", segment, template))
+        } else {
+            Ok(Segment::Static(segment))
+        }
+    }).collect()
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    match &path[1..] {
+        "" => vec![],
+        rest => rest.split('/').collect()
+    }
+}
+
+/// A route registered with a `Router`.
+pub struct Endpoint<T> {
+    /// The route template, e.g. `/session/{sessionId}/url`.
+    pub template: String,
+    /// Names of the path parameters, in the order they appear.
+    pub params: Vec<String>,
+    pub value: T,
+}
+
+struct Node<T> {
+    statics: HashMap<String, Node<T>>,
+    params: Vec<(ParamType, Node<T>)>,
+    endpoints: Vec<(Method, Endpoint<T>)>,
+}
+
+impl<T> Node<T> {
+    fn new() -> Node<T> {
+        Node {
+            statics: HashMap::new(),
+            params: vec![],
+            endpoints: vec![],
+        }
+    }
+
+    fn param_child(&mut self, param_type: ParamType) -> &mut Node<T> {
+        if !self.params.iter().any(|&(x, _)| x == param_type) {
+            self.params.push((param_type, Node::new()));
+            self.params.sort_by_key(|&(x, _)| x);
+        }
+        let index = self.params.iter().position(|&(x, _)| x == param_type).unwrap();
+        &mut self.params[index].1
+    }
+
+    /// Find the endpoint for `method` at the path made up of `segments`.
+    ///
+    /// Static segments are preferred over parameters. The values of the
+    /// parameters on the way to the endpoint are collected in `values`, and
+    /// if the path exists only for other methods, those are put in `allowed`.
+    fn find<'a, 'p>(&'a self,
+                    method: &Method,
+                    segments: &[&'p str],
+                    values: &mut Vec<&'p str>,
+                    allowed: &mut Vec<Method>)
+                    -> Option<&'a Endpoint<T>> {
+        let (segment, rest) = match segments.split_first() {
+            Some(x) => x,
+            None => {
+                if let Some(&(_, ref endpoint)) = self.endpoints.iter().find(|x| x.0 == *method) {
+                    return Some(endpoint)
+                }
+                if allowed.is_empty() {
+                    allowed.extend(self.endpoints.iter().map(|x| x.0.clone()));
+                }
+                return None
+            }
+        };
+        if let Some(child) = self.statics.get(*segment) {
+            if let Some(endpoint) = child.find(method, rest, values, allowed) {
+                return Some(endpoint)
+            }
+        }
+        for &(param_type, ref child) in self.params.iter() {
+            if param_type.accepts(segment) {
+                values.push(segment);
+                if let Some(endpoint) = child.find(method, rest, values, allowed) {
+                    return Some(endpoint)
+                }
+                values.pop();
+            }
+        }
+        None
+    }
+
+    /// Collect the methods of every endpoint at the path made up of
+    /// `segments`, whichever way its segments are matched.
+    fn methods(&self, segments: &[&str], methods: &mut Vec<Method>) {
+        let (segment, rest) = match segments.split_first() {
+            Some(x) => x,
+            None => {
+                for &(ref method, _) in self.endpoints.iter() {
+                    if !methods.contains(method) {
+                        methods.push(method.clone());
+                    }
+                }
+                return
+            }
+        };
+        if let Some(child) = self.statics.get(*segment) {
+            child.methods(rest, methods);
+        }
+        for &(param_type, ref child) in self.params.iter() {
+            if param_type.accepts(segment) {
+                child.methods(rest, methods);
+            }
+        }
+    }
+}
+
+/// Maps request methods and paths to values, using a trie keyed on path
+/// segments.
+pub struct Router<T> {
+    root: Node<T>,
+}
+
+impl<T> Router<T> {
+    pub fn new() -> Router<T> {
+        Router {
+            root: Node::new(),
+        }
+    }
+
+    /// Add a route for `method` and the path `template`.
+    ///
+    /// Fails if the template is malformed, or if there is already a route
+    /// for `method` with the same shape, i.e. one that differs at most in
+    /// the names of its parameters.
+    pub fn insert(&mut self, method: Method, template: &str, value: T) -> Result<(), String> {
+        let segments = parse_template(template)?;
+        let mut params = vec![];
+        let mut node = &mut self.root;
+        for segment in segments {
+            node = match segment {
+                Segment::Static(name) => {
+                    node.statics.entry(name.to_string()).or_insert_with(Node::new)
+                },
+                Segment::Param(name, param_type) => {
+                    params.push(name.to_string());
+                    node.param_child(param_type)
+                }
+            };
+        }
+        if let Some(&(_, ref existing)) = node.endpoints.iter().find(|x| x.0 == method) {
+            return Err(format!("Route {} {} conflicts with {} {}",
+                               method, template, method, existing.template))
+        }
+        node.endpoints.push((method, Endpoint {
+            template: template.to_string(),
+            params: params,
+            value: value,
+        }));
+        Ok(())
+    }
+
+    /// Find the endpoint for a request, along with the raw values of its
+    /// path parameters.
+    ///
+    /// If there is no match, the error holds the methods that the path is
+    /// available under, which is empty if the path is unknown altogether.
+    pub fn lookup<'a, 'p>(&'a self, method: &Method, path: &'p str)
+                          -> Result<(&'a Endpoint<T>, Vec<&'p str>), Vec<Method>> {
+        let mut allowed = vec![];
+        if !path.starts_with('/') {
+            return Err(allowed)
+        }
+        let segments = split_path(path);
+        let mut values = Vec::with_capacity(segments.len());
+        match self.root.find(method, &segments, &mut values, &mut allowed) {
+            Some(endpoint) => Ok((endpoint, values)),
+            None => Err(allowed)
+        }
+    }
+
+    /// The methods that `path` can be looked up with.
+    pub fn methods_for(&self, path: &str) -> Vec<Method> {
+        let mut methods = vec![];
+        if path.starts_with('/') {
+            self.root.methods(&split_path(path), &mut methods);
+        }
+        methods
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::method::Method::{Delete, Get, Post};
+
+    use super::Router;
+
+    fn router() -> Router<u32> {
+        let mut router = Router::new();
+        router.insert(Get, "/", 0).unwrap();
+        router.insert(Get, "/session/{sessionId}/element/active", 1).unwrap();
+        router.insert(Get, "/session/{sessionId}/element/{elementId}/text", 2).unwrap();
+        router.insert(Post, "/session/{sessionId}/element/{elementId}/text", 3).unwrap();
+        router.insert(Get, "/session/{sessionId}/window/{index:uint}", 4).unwrap();
+        router.insert(Get, "/session/{sessionId}/window/{name}", 5).unwrap();
+        router.insert(Get, "/session/{sessionId}/offset/{x:int}", 6).unwrap();
+        router
+    }
+
+    fn lookup(router: &Router<u32>, path: &str) -> Option<(u32, Vec<String>)> {
+        router.lookup(&Get, path).ok().map(|(endpoint, values)| {
+            (endpoint.value, values.iter().map(|x| x.to_string()).collect())
+        })
+    }
+
+    #[test]
+    fn test_lookup() {
+        let router = router();
+        assert_eq!(lookup(&router, "/"), Some((0, vec![])));
+        assert_eq!(lookup(&router, "/session/1/element/active"), Some((1, vec!["1".into()])));
+        assert_eq!(lookup(&router, "/session/1/element/e/text"),
+                   Some((2, vec!["1".into(), "e".into()])));
+        assert_eq!(lookup(&router, "/session/1/element/active/text"),
+                   Some((2, vec!["1".into(), "active".into()])));
+        assert_eq!(lookup(&router, "/session/1/element//text"), None);
+        assert_eq!(lookup(&router, "/session/1/element"), None);
+        assert_eq!(lookup(&router, "session/1/element/active"), None);
+    }
+
+    #[test]
+    fn test_methods_for() {
+        let router = router();
+        assert_eq!(router.methods_for("/session/1/element/e/text"), vec![Get, Post]);
+        assert_eq!(router.methods_for("/session/1/window/3"), vec![Get]);
+        assert_eq!(router.methods_for("/session/1/element"), vec![]);
+        assert_eq!(router.methods_for("session"), vec![]);
+
+        let mut router = router;
+        router.insert(Delete, "/session/{sessionId}/window/{index:uint}", 7).unwrap();
+        router.insert(Post, "/session/{sessionId}/window/{name}", 8).unwrap();
+        assert_eq!(router.methods_for("/session/1/window/3"), vec![Get, Delete, Post]);
+        assert_eq!(router.methods_for("/session/1/window/main"), vec![Get, Post]);
+    }
+
+    #[test]
+    fn test_typed_params() {
+        let router = router();
+        assert_eq!(lookup(&router, "/session/1/window/3").map(|x| x.0), Some(4));
+        assert_eq!(lookup(&router, "/session/1/window/%33").map(|x| x.0), Some(4));
+        assert_eq!(lookup(&router, "/session/1/window/-3").map(|x| x.0), Some(5));
+        assert_eq!(lookup(&router, "/session/1/window/main").map(|x| x.0), Some(5));
+        assert_eq!(lookup(&router, "/session/1/offset/-3").map(|x| x.0), Some(6));
+        assert_eq!(lookup(&router, "/session/1/offset/x"), None);
+    }
+
+    #[test]
+    fn test_allowed_methods() {
+        let router = router();
+        let err = router.lookup(&Delete, "/session/1/element/e/text").err().unwrap();
+        assert_eq!(err, vec![Get, Post]);
+        let err = router.lookup(&Delete, "/session/1/unknown").err().unwrap();
+        assert_eq!(err, vec![]);
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let mut router = router();
+        assert!(router.insert(Get, "/session/{sessionId}/element/active", 10).is_err());
+        assert!(router.insert(Get, "/session/{id}/element/{element}/text", 10).is_err());
+        assert!(router.insert(Get, "/session/{sessionId}/window/{n:uint}", 10).is_err());
+        assert!(router.insert(Delete, "/session/{id}/element/{element}/text", 10).is_ok());
+        assert!(router.insert(Get, "/session/{sessionId}/offset/{x:float}", 10).is_err());
+        assert!(router.insert(Get, "/session/{sessionId/url", 10).is_err());
+        assert!(router.insert(Get, "session", 10).is_err());
+    }
+}
@@ -1,28 +1,63 @@
-use std::io::Read;
+use std::any::Any;
+use std::cmp;
+use std::io::{self, BufWriter, Read, Write};
+use std::mem;
 use std::marker::PhantomData;
-use std::net::SocketAddr;
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::Mutex;
-use std::thread;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::result;
+use std::collections::{BTreeMap, BTreeSet};
+#[cfg(any(unix, feature = "tls"))]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use hyper::header::{ContentType, CacheControl, CacheDirective};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use hyper::header::{AcceptEncoding, Allow, Authorization, Basic, Bearer, Connection, ContentEncoding,
+                    ContentLength, ContentType, CacheControl, CacheDirective, Encoding, Headers,
+                    Host};
 use hyper::mime::{Mime, TopLevel, SubLevel, Attr, Value};
 use hyper::method::Method;
 use hyper::Result;
+#[cfg(feature = "tls")]
+use hyper::net::HttpsListener;
+use hyper::net::{HttpListener, NetworkListener, Streaming};
 use hyper::server::{Handler, Listening, Request, Response, Server};
 use hyper::status::StatusCode;
 use hyper::uri::RequestUri::AbsolutePath;
+use log::LogLevel;
+use rustc_serialize::json::{Json, ToJson};
 
-use command::{WebDriverMessage, WebDriverCommand};
+use command::{WebDriverMessage, WebDriverCommand, WebDriverExtensionCommand,
+              TimeoutsParameters};
 use error::{WebDriverResult, WebDriverError, ErrorStatus};
 use httpapi::{WebDriverHttpApi, WebDriverExtensionRoute, VoidWebDriverExtensionRoute};
-use response::{CloseWindowResponse, WebDriverResponse};
+use metrics::Metrics;
+use response::{CloseWindowResponse, ValueResponse, WebDriverResponse};
+#[cfg(feature = "tls")]
+use tls::TlsServer;
+#[cfg(unix)]
+use unix::UnixHttpListener;
 
 enum DispatchMessage<U: WebDriverExtensionRoute> {
-    HandleWebDriver(WebDriverMessage<U>, Sender<WebDriverResult<WebDriverResponse>>),
+    HandleWebDriver(u64, WebDriverMessage<U>, Sender<DispatchReply>),
     Quit
 }
 
+/// What the dispatcher sends back about a command.
+enum DispatchReply {
+    /// The dispatcher has started on the command; until then it is queued
+    /// behind earlier ones.
+    Started,
+    Done(WebDriverResult<WebDriverResponse>),
+}
+
 #[derive(PartialEq, Clone)]
 pub struct Session {
     id: String
@@ -36,39 +71,309 @@ impl Session {
     }
 }
 
+/// Timeouts of the current session, as needed to work out command deadlines.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct SessionTimeouts {
+    /// Script timeout in ms, or `None` if scripts may run indefinitely
+    script: Option<u64>,
+    page_load: u64,
+    implicit: u64,
+}
+
+impl Default for SessionTimeouts {
+    fn default() -> SessionTimeouts {
+        SessionTimeouts {
+            script: Some(30000),
+            page_load: 300000,
+            implicit: 0,
+        }
+    }
+}
+
+impl SessionTimeouts {
+    fn from_capabilities(capabilities: &Json) -> SessionTimeouts {
+        let mut timeouts = SessionTimeouts::default();
+        if let Some(data) = capabilities.find("timeouts") {
+            if let Some(script) = data.find("script") {
+                timeouts.script = script.as_u64();
+            }
+            if let Some(page_load) = data.find("pageLoad").and_then(|x| x.as_u64()) {
+                timeouts.page_load = page_load;
+            }
+            if let Some(implicit) = data.find("implicit").and_then(|x| x.as_u64()) {
+                timeouts.implicit = implicit;
+            }
+        }
+        timeouts
+    }
+
+    fn update(&mut self, params: &TimeoutsParameters) {
+        if params.script.is_some() {
+            self.script = params.script;
+        }
+        if let Some(page_load) = params.page_load {
+            self.page_load = page_load;
+        }
+        if let Some(implicit) = params.implicit {
+            self.implicit = implicit;
+        }
+    }
+
+    /// Time in ms that the session timeouts allow `command` to take, or
+    /// `None` if they don't bound it.
+    fn allowed_duration<T: WebDriverExtensionCommand>(&self, command: &WebDriverCommand<T>) -> Option<u64> {
+        match *command {
+            WebDriverCommand::Get(_) |
+            WebDriverCommand::GoBack |
+            WebDriverCommand::GoForward |
+            WebDriverCommand::Refresh |
+            WebDriverCommand::ElementClick(_) => Some(self.page_load),
+            WebDriverCommand::ExecuteScript(_) |
+            WebDriverCommand::ExecuteAsyncScript(_) => self.script,
+            WebDriverCommand::FindElement(_) |
+            WebDriverCommand::FindElements(_) |
+            WebDriverCommand::FindElementElement(_, _) |
+            WebDriverCommand::FindElementElements(_, _) => Some(self.implicit),
+            _ => None,
+        }
+    }
+}
+
+/// Progress of commands through the dispatcher, used to tell which commands
+/// overran their deadline.
+///
+/// Commands are numbered in the order they are sent to the dispatcher, which
+/// handles them in that same order.
+#[derive(Default)]
+struct CommandProgress {
+    /// Id of the last command sent to the dispatcher
+    sent: u64,
+    /// Id of the last command the dispatcher completed
+    completed: u64,
+    /// Commands that overran their deadline and have not yet completed
+    stuck: BTreeSet<u64>,
+}
+
+/// State shared between the HTTP handlers and the dispatcher thread.
+///
+/// This is also what the status endpoint reports from, so that it can be
+/// answered without waiting on the handler.
+struct ServerState {
+    timeouts: Mutex<SessionTimeouts>,
+    /// The handler is presumed stuck while any command has overrun its
+    /// deadline. The session is deleted once such a command returns.
+    progress: Mutex<CommandProgress>,
+    sessions: AtomicUsize,
+    status_message: Mutex<Option<String>>,
+    /// Number of HTTP requests currently being handled
+    requests: AtomicUsize,
+    refuse_new_sessions: bool,
+}
+
+impl ServerState {
+    fn new() -> ServerState {
+        ServerState {
+            timeouts: Mutex::new(SessionTimeouts::default()),
+            progress: Mutex::new(CommandProgress::default()),
+            sessions: AtomicUsize::new(0),
+            status_message: Mutex::new(None),
+            requests: AtomicUsize::new(0),
+            refuse_new_sessions: false,
+        }
+    }
+
+    /// Body of the status endpoint.
+    ///
+    /// The server is ready when it can create a new session, which is only
+    /// the case while no session is running.
+    fn status(&self) -> Json {
+        let sessions = self.sessions.load(Ordering::SeqCst);
+        let unhealthy = self.unhealthy();
+        let ready = sessions == 0 && !unhealthy && !self.refuse_new_sessions;
+        let handler_message = self.status_message.lock().ok().and_then(|x| x.clone());
+        let message = match handler_message {
+            Some(message) => message,
+            None if unhealthy => "Session is unresponsive".to_string(),
+            None if self.refuse_new_sessions => "Not accepting new sessions".to_string(),
+            None if !ready => "Session already started".to_string(),
+            None => "".to_string(),
+        };
+
+        let mut data = BTreeMap::new();
+        data.insert("ready".to_string(), ready.to_json());
+        data.insert("message".to_string(), message.to_json());
+        data.insert("sessions".to_string(), sessions.to_json());
+        Json::Object(data)
+    }
+
+    fn unhealthy(&self) -> bool {
+        self.progress.lock().map(|x| !x.stuck.is_empty()).unwrap_or(false)
+    }
+
+    /// Allocate the id of the next command sent to the dispatcher.
+    fn next_command(&self) -> u64 {
+        match self.progress.lock() {
+            Ok(mut progress) => {
+                progress.sent += 1;
+                progress.sent
+            },
+            Err(_) => 0
+        }
+    }
+
+    /// Record that command `id` overran its deadline. Returns `false` if it
+    /// already completed, in which case its response is on the way.
+    fn mark_stuck(&self, id: u64) -> bool {
+        match self.progress.lock() {
+            Ok(mut progress) => {
+                if progress.completed >= id {
+                    return false;
+                }
+                progress.stuck.insert(id);
+                true
+            },
+            Err(_) => true
+        }
+    }
+
+    /// Record that command `id` completed. Returns `true` if it had overrun
+    /// its deadline; it then counts as stuck until `recovered` is called.
+    fn complete(&self, id: u64) -> bool {
+        match self.progress.lock() {
+            Ok(mut progress) => {
+                progress.completed = id;
+                progress.stuck.contains(&id)
+            },
+            Err(_) => false
+        }
+    }
+
+    fn recovered(&self, id: u64) {
+        if let Ok(mut progress) = self.progress.lock() {
+            progress.stuck.remove(&id);
+        }
+    }
+
+    fn set_timeouts(&self, timeouts: SessionTimeouts) {
+        if let Ok(mut current) = self.timeouts.lock() {
+            *current = timeouts;
+        }
+    }
+}
+
+/// Counts an HTTP request as in flight for as long as it is alive.
+struct InFlight<'a>(&'a ServerState);
+
+impl<'a> InFlight<'a> {
+    fn new(state: &'a ServerState) -> InFlight<'a> {
+        state.requests.fetch_add(1, Ordering::SeqCst);
+        InFlight(state)
+    }
+}
+
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        self.0.requests.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Function returning a fixed deadline for some commands.
+type CommandDeadline<U> = dyn Fn(&WebDriverCommand<<U as WebDriverExtensionRoute>::Command>)
+                                 -> Option<Duration> + Send + Sync;
+
 pub trait WebDriverHandler<U: WebDriverExtensionRoute=VoidWebDriverExtensionRoute> : Send {
     fn handle_command(&mut self, session: &Option<Session>, msg: WebDriverMessage<U>) -> WebDriverResult<WebDriverResponse>;
     fn delete_session(&mut self, session: &Option<Session>);
+
+    /// Message to report from the status endpoint.
+    ///
+    /// The `Status` command is answered by the server itself and is never
+    /// passed to `handle_command`. This is queried when the server starts and
+    /// after every command, so the status endpoint does not have to wait for
+    /// the handler.
+    fn status_message(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Information about a command being dispatched, passed to middleware.
+pub struct CommandContext {
+    /// Id of the session the server is running, if any.
+    ///
+    /// This may differ from `WebDriverMessage::session_id`, which is the id
+    /// supplied by the client.
+    pub session_id: Option<String>,
+    /// When the dispatcher started processing the command.
+    pub started: Instant,
+}
+
+/// Hook run by the dispatcher around every command.
+///
+/// Middleware is run in the order it was registered with
+/// `ServerBuilder::middleware`: `before` runs first to last, before the
+/// session is checked and the command passed to the handler, and `after`
+/// runs last to first once there is a result. Both run on the dispatcher
+/// thread, so they should not block for long. A panic in either is turned
+/// into an `UnknownError` result.
+pub trait CommandMiddleware<U: WebDriverExtensionRoute=VoidWebDriverExtensionRoute> : Send {
+    /// Inspect or rewrite a command before it is handled.
+    ///
+    /// Returning an error skips the handler and any later middleware; the
+    /// error becomes the result passed to `after`.
+    fn before(&mut self, _msg: &mut WebDriverMessage<U>, _context: &CommandContext)
+              -> WebDriverResult<()> {
+        Ok(())
+    }
+
+    /// Inspect or rewrite the result of a command.
+    ///
+    /// `msg` is the command as it was passed to the handler, after any
+    /// rewriting by `before`.
+    fn after(&mut self,
+             _msg: &WebDriverMessage<U>,
+             _result: &mut WebDriverResult<WebDriverResponse>,
+             _context: &CommandContext) {
+    }
 }
 
 struct Dispatcher<T: WebDriverHandler<U>,
                   U: WebDriverExtensionRoute> {
     handler: T,
     session: Option<Session>,
+    state: Arc<ServerState>,
+    middleware: Vec<Box<dyn CommandMiddleware<U>>>,
     extension_type: PhantomData<U>,
 }
 
 impl<T: WebDriverHandler<U>, U: WebDriverExtensionRoute> Dispatcher<T, U> {
-    fn new(handler: T) -> Dispatcher<T, U> {
+    fn new(handler: T, state: Arc<ServerState>) -> Dispatcher<T, U> {
         Dispatcher {
             handler: handler,
             session: None,
+            state: state,
+            middleware: vec![],
             extension_type: PhantomData,
         }
     }
 
     fn run(&mut self, msg_chan: Receiver<DispatchMessage<U>>) {
+        self.update_status();
         loop {
             match msg_chan.recv() {
-                Ok(DispatchMessage::HandleWebDriver(msg, resp_chan)) => {
-                    let resp = match self.check_session(&msg) {
-                        Ok(_) => self.handler.handle_command(&self.session, msg),
-                        Err(e) => Err(e),
+                Ok(DispatchMessage::HandleWebDriver(id, msg, resp_chan)) => {
+                    // The HTTP thread may have given up on the command
+                    let _ = resp_chan.send(DispatchReply::Started);
+                    let resp = if self.middleware.is_empty() {
+                        self.process(msg)
+                    } else {
+                        self.process_with_middleware(msg)
                     };
 
                     match resp {
                         Ok(WebDriverResponse::NewSession(ref new_session)) => {
                             self.session = Some(Session::new(new_session.sessionId.clone()));
+                            self.state.set_timeouts(
+                                SessionTimeouts::from_capabilities(&new_session.capabilities));
                         }
                         Ok(WebDriverResponse::CloseWindow(CloseWindowResponse { ref window_handles })) => {
                             if window_handles.len() == 0 {
@@ -81,22 +386,137 @@ impl<T: WebDriverHandler<U>, U: WebDriverExtensionRoute> Dispatcher<T, U> {
                         _ => {}
                     }
 
-                    if resp_chan.send(resp).is_err() {
+                    if self.state.complete(id) {
+                        debug!("Command overran its deadline, deleting session");
+                        if self.session.is_some() {
+                            self.delete_session();
+                        }
+                        self.state.recovered(id);
+                    }
+
+                    self.update_status();
+
+                    if resp_chan.send(DispatchReply::Done(resp)).is_err() {
                         error!("Sending response to the main thread failed");
                     };
                 }
-                Ok(DispatchMessage::Quit) => break,
+                Ok(DispatchMessage::Quit) => {
+                    if self.session.is_some() {
+                        debug!("Server shutting down, deleting session");
+                        self.delete_session();
+                    }
+                    break
+                },
                 Err(_) => panic!("Error receiving message in handler"),
             }
         }
     }
 
+    fn process(&mut self, msg: WebDriverMessage<U>) -> WebDriverResult<WebDriverResponse> {
+        self.check_session(&msg)?;
+
+        let new_timeouts = match msg.command {
+            WebDriverCommand::SetTimeouts(ref params) => {
+                let mut timeouts = self.state.timeouts.lock()
+                    .map(|x| *x)
+                    .unwrap_or_default();
+                timeouts.update(params);
+                Some(timeouts)
+            },
+            _ => None
+        };
+
+        let resp = self.handle_command(msg);
+        if let (true, Some(timeouts)) = (resp.is_ok(), new_timeouts) {
+            self.state.set_timeouts(timeouts);
+        }
+        resp
+    }
+
+    fn process_with_middleware(&mut self, mut msg: WebDriverMessage<U>)
+                               -> WebDriverResult<WebDriverResponse> {
+        let context = CommandContext {
+            session_id: self.session.as_ref().map(|x| x.id.clone()),
+            started: Instant::now(),
+        };
+
+        let mut ran = 0;
+        let mut rejected = None;
+        for middleware in self.middleware.iter_mut() {
+            ran += 1;
+            let before = panic::catch_unwind(AssertUnwindSafe(|| middleware.before(&mut msg, &context)));
+            if let Err(err) = before.unwrap_or_else(|payload| Err(middleware_panic(&payload))) {
+                rejected = Some(err);
+                break;
+            }
+        }
+
+        // The handler takes the message, so keep a copy for `after`
+        let (msg, mut resp) = match rejected {
+            Some(err) => (msg, Err(err)),
+            None => (msg.clone(), self.process(msg)),
+        };
+        for middleware in self.middleware[..ran].iter_mut().rev() {
+            let after = panic::catch_unwind(AssertUnwindSafe(|| {
+                middleware.after(&msg, &mut resp, &context)
+            }));
+            if let Err(payload) = after {
+                resp = Err(middleware_panic(&payload));
+            }
+        }
+        resp
+    }
+
+    /// Run a command in the handler, turning a panic into an error that
+    /// deletes the session so that the dispatcher survives for later ones.
+    fn handle_command(&mut self, msg: WebDriverMessage<U>) -> WebDriverResult<WebDriverResponse> {
+        let handler = &mut self.handler;
+        let session = &self.session;
+        match panic::catch_unwind(AssertUnwindSafe(|| handler.handle_command(session, msg))) {
+            Ok(resp) => resp,
+            Err(payload) => {
+                let message = panic_message(&payload);
+                error!("Handler panicked while handling command: {}", message);
+                let mut err = WebDriverError::new(
+                    ErrorStatus::UnknownError,
+                    format!("Handler panicked while handling command: {}", message));
+                err.delete_session = true;
+                Err(err)
+            }
+        }
+    }
+
+    fn live_sessions(&self) -> usize {
+        if self.session.is_some() { 1 } else { 0 }
+    }
+
+    fn update_status(&self) {
+        self.state.sessions.store(self.live_sessions(), Ordering::SeqCst);
+        let handler = &self.handler;
+        match panic::catch_unwind(AssertUnwindSafe(|| handler.status_message())) {
+            Ok(message) => {
+                if let Ok(mut status_message) = self.state.status_message.lock() {
+                    *status_message = message;
+                }
+            },
+            Err(payload) => {
+                error!("Handler panicked while getting status: {}", panic_message(&payload));
+            }
+        }
+    }
+
     fn delete_session(&mut self) {
         debug!("Deleting session");
-        self.handler.delete_session(&self.session);
+        let handler = &mut self.handler;
+        let session = &self.session;
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| handler.delete_session(session))) {
+            error!("Handler panicked while deleting session: {}", panic_message(&payload));
+        }
         self.session = None;
+        self.state.set_timeouts(SessionTimeouts::default());
     }
 
+    // `Status` is answered by `HttpHandler::dispatch` and never gets here
     fn check_session(&self, msg: &WebDriverMessage<U>) -> WebDriverResult<()> {
         match msg.session_id {
             Some(ref msg_session_id) => {
@@ -119,7 +539,6 @@ impl<T: WebDriverHandler<U>, U: WebDriverExtensionRoute> Dispatcher<T, U> {
                 match self.session {
                     Some(_) => {
                         match msg.command {
-                            WebDriverCommand::Status => Ok(()),
                             WebDriverCommand::NewSession(_) => {
                                 Err(WebDriverError::new(
                                     ErrorStatus::SessionNotCreated,
@@ -136,8 +555,15 @@ impl<T: WebDriverHandler<U>, U: WebDriverExtensionRoute> Dispatcher<T, U> {
                     },
                     None => {
                         match msg.command {
-                            WebDriverCommand::NewSession(_) => Ok(()),
-                            WebDriverCommand::Status => Ok(()),
+                            WebDriverCommand::NewSession(_) => {
+                                if self.state.refuse_new_sessions {
+                                    Err(WebDriverError::new(
+                                        ErrorStatus::SessionNotCreated,
+                                        "Server is not accepting new sessions"))
+                                } else {
+                                    Ok(())
+                                }
+                            },
                             _ => Err(WebDriverError::new(
                                 ErrorStatus::InvalidSessionId,
                                 "Tried to run a command before creating a session"))
@@ -149,16 +575,386 @@ impl<T: WebDriverHandler<U>, U: WebDriverExtensionRoute> Dispatcher<T, U> {
     }
 }
 
+fn middleware_panic(payload: &Box<dyn Any + Send>) -> WebDriverError {
+    let message = panic_message(payload);
+    error!("Middleware panicked: {}", message);
+    WebDriverError::new(ErrorStatus::UnknownError, format!("Middleware panicked: {}", message))
+}
+
+fn panic_message(payload: &Box<dyn Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Credentials that clients must supply in the `Authorization` header.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Authentication {
+    /// `Authorization: Bearer <token>`
+    Bearer(String),
+    /// `Authorization: Basic <base64 of username:password>`
+    Basic {
+        username: String,
+        password: String,
+    },
+}
+
+impl Authentication {
+    fn check(&self, headers: &Headers) -> bool {
+        match *self {
+            Authentication::Bearer(ref token) => {
+                match headers.get::<Authorization<Bearer>>() {
+                    Some(&Authorization(Bearer { token: ref given })) => {
+                        constant_time_eq(given.as_bytes(), token.as_bytes())
+                    },
+                    None => false
+                }
+            },
+            Authentication::Basic { ref username, ref password } => {
+                match headers.get::<Authorization<Basic>>() {
+                    Some(&Authorization(Basic { username: ref given_user,
+                                                password: ref given_password })) => {
+                        let given_password = given_password.as_ref().map(|x| &x[..]).unwrap_or("");
+                        // Evaluate both so the timing doesn't reveal which one was wrong
+                        let user_ok = constant_time_eq(given_user.as_bytes(), username.as_bytes());
+                        let password_ok = constant_time_eq(given_password.as_bytes(),
+                                                           password.as_bytes());
+                        user_ok & password_ok
+                    },
+                    None => false
+                }
+            }
+        }
+    }
+
+    fn challenge(&self) -> &'static str {
+        match *self {
+            Authentication::Bearer(_) => "Bearer realm=\"webdriver\"",
+            Authentication::Basic { .. } => "Basic realm=\"webdriver\"",
+        }
+    }
+}
+
+/// Compare secrets without exiting early on the first difference.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Default limit on the size of request bodies, in bytes.
+const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+/// Default size in bytes from which responses are compressed.
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 16 * 1024;
+
+/// Size in bytes from which responses are streamed rather than sent with a
+/// `Content-Length` when compression is off, and the size of the buffer
+/// used when streaming.
+const RESPONSE_BUFFER_SIZE: usize = 16 * 1024;
+
+/// How much of each response body is logged at debug level.
+const LOGGED_BODY_SIZE: usize = 1024;
+
+fn default_allowed_hosts() -> Vec<String> {
+    vec!["localhost".to_string()]
+}
+
 struct HttpHandler<U: WebDriverExtensionRoute> {
     chan: Mutex<Sender<DispatchMessage<U>>>,
-    api: Mutex<WebDriverHttpApi<U>>
+    api: Mutex<WebDriverHttpApi<U>>,
+    shutdown: Arc<AtomicBool>,
+    state: Arc<ServerState>,
+    max_body_size: Option<usize>,
+    compression_threshold: Option<usize>,
+    allowed_hosts: Vec<String>,
+    allowed_origins: Vec<String>,
+    deadline_margin: Option<Duration>,
+    command_deadline: Option<Box<CommandDeadline<U>>>,
+    metrics_path: Option<String>,
+    metrics: Option<Metrics>,
+    authentication: Option<Authentication>,
 }
 
 impl <U: WebDriverExtensionRoute> HttpHandler<U> {
-    fn new(api: WebDriverHttpApi<U>, chan: Sender<DispatchMessage<U>>) -> HttpHandler<U> {
+    fn new(api: WebDriverHttpApi<U>,
+           chan: Sender<DispatchMessage<U>>,
+           shutdown: Arc<AtomicBool>,
+           state: Arc<ServerState>) -> HttpHandler<U> {
         HttpHandler {
             chan: Mutex::new(chan),
-            api: Mutex::new(api)
+            api: Mutex::new(api),
+            shutdown: shutdown,
+            state: state,
+            max_body_size: Some(DEFAULT_MAX_BODY_SIZE),
+            compression_threshold: Some(DEFAULT_COMPRESSION_THRESHOLD),
+            allowed_hosts: default_allowed_hosts(),
+            allowed_origins: vec![],
+            deadline_margin: None,
+            command_deadline: None,
+            metrics_path: None,
+            metrics: None,
+            authentication: None,
+        }
+    }
+
+    fn send_metrics(&self, mut res: Response) {
+        let body = match self.metrics {
+            Some(ref metrics) => metrics.render(self.state.sessions.load(Ordering::SeqCst)),
+            None => String::new()
+        };
+        res.headers_mut().set(
+            ContentType(Mime(TopLevel::Text, SubLevel::Plain,
+                             vec![(Attr::Ext("version".into()), Value::Ext("0.0.4".into()))])));
+        res.headers_mut().set(CacheControl(vec![CacheDirective::NoCache]));
+        if let Err(err) = res.send(body.as_bytes()) {
+            error!("Failed to send metrics: {}", err);
+        }
+    }
+
+    /// How long to wait for the dispatcher to answer `command`, if at all.
+    fn deadline(&self, command: &WebDriverCommand<U::Command>) -> Option<Duration> {
+        if let Some(ref command_deadline) = self.command_deadline {
+            if let Some(deadline) = command_deadline(command) {
+                return Some(deadline);
+            }
+        }
+        let margin = match self.deadline_margin {
+            Some(margin) => margin,
+            None => return None
+        };
+        let timeouts = self.state.timeouts.lock().map(|x| *x).unwrap_or_default();
+        timeouts.allowed_duration(command).map(|ms| Duration::from_millis(ms) + margin)
+    }
+
+    fn dispatch(&self, message: WebDriverMessage<U>) -> WebDriverResult<WebDriverResponse> {
+        if let WebDriverCommand::Status = message.command {
+            return Ok(WebDriverResponse::Generic(ValueResponse::new(self.state.status())));
+        }
+
+        if self.state.unhealthy() {
+            return Err(WebDriverError::new(
+                ErrorStatus::UnknownError,
+                "Session is unresponsive: a previous command did not complete"));
+        }
+
+        let deadline = self.deadline(&message.command);
+        let (send_res, recv_res) = channel();
+        let mut id = 0;
+        let sent = match self.chan.lock() {
+            Ok(ref c) => {
+                // Allocated while holding the channel so ids follow the order
+                // the dispatcher sees
+                id = self.state.next_command();
+                c.send(DispatchMessage::HandleWebDriver(id, message, send_res)).is_ok()
+            },
+            Err(_) => false
+        };
+        if !sent {
+            error!("Failed to send command to the dispatcher");
+            return Err(WebDriverError::new(ErrorStatus::UnknownError,
+                                           "Dispatcher is not running"));
+        }
+
+        let exited = || {
+            WebDriverError::new(ErrorStatus::UnknownError,
+                                "Dispatcher exited without a response")
+        };
+        // The deadline counts from when the dispatcher starts on the command,
+        // not while it is queued behind earlier ones
+        match recv_res.recv() {
+            Ok(DispatchReply::Started) => {},
+            Ok(DispatchReply::Done(data)) => return data,
+            Err(_) => return Err(exited())
+        }
+        let done = || match recv_res.recv() {
+            Ok(DispatchReply::Done(data)) => data,
+            _ => Err(exited())
+        };
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => return done()
+        };
+        match recv_res.recv_timeout(deadline) {
+            Ok(DispatchReply::Done(data)) => data,
+            Err(RecvTimeoutError::Timeout) => {
+                // Only the command the dispatcher is running can be stuck, and
+                // that is this one unless it has just completed
+                if !self.state.mark_stuck(id) {
+                    return done();
+                }
+                error!("Command did not complete within {:?}", deadline);
+                Err(WebDriverError::new(
+                    ErrorStatus::Timeout,
+                    format!("Command did not complete within {} ms", deadline.as_millis())))
+            },
+            Ok(DispatchReply::Started) | Err(RecvTimeoutError::Disconnected) => Err(exited())
+        }
+    }
+
+    fn check_request(&self, headers: &Headers) -> WebDriverResult<()> {
+        if self.shutdown.load(Ordering::SeqCst) {
+            return Err(WebDriverError::new(ErrorStatus::UnknownError,
+                                           "Server is shutting down"));
+        }
+        self.check_host(headers)?;
+        self.check_origin(headers)
+    }
+
+    /// Guard against DNS rebinding by only accepting requests addressed to
+    /// a loopback IP address or one of the allowed host names.
+    fn check_host(&self, headers: &Headers) -> WebDriverResult<()> {
+        let host = match headers.get::<Host>() {
+            Some(host) => host,
+            None => {
+                return Err(WebDriverError::new(ErrorStatus::InvalidArgument,
+                                               "Missing Host header"))
+            }
+        };
+        let hostname = host.hostname.trim_start_matches('[').trim_end_matches(']');
+        let is_loopback = hostname.parse::<IpAddr>().map(|x| x.is_loopback()).unwrap_or(false);
+        if is_loopback || self.allowed_hosts.iter().any(|x| x == hostname) {
+            Ok(())
+        } else {
+            Err(WebDriverError::new(
+                ErrorStatus::InvalidArgument,
+                format!("Invalid Host header {}: not a loopback address or allowed host name",
+                        host.hostname)))
+        }
+    }
+
+    /// Reject requests made from web content, which browsers mark with an
+    /// `Origin` header, unless the origin is explicitly allowed.
+    fn check_origin(&self, headers: &Headers) -> WebDriverResult<()> {
+        let origin = match headers.get_raw("Origin") {
+            Some(values) => values.iter()
+                .map(|x| String::from_utf8_lossy(x).into_owned())
+                .collect::<Vec<_>>()
+                .join(", "),
+            None => return Ok(())
+        };
+        if self.allowed_origins.iter().any(|x| *x == origin) {
+            Ok(())
+        } else {
+            Err(WebDriverError::new(ErrorStatus::InvalidArgument,
+                                    format!("Invalid Origin header {}: origin not allowed",
+                                            origin)))
+        }
+    }
+
+    /// Read the request body, enforcing the size limit and checking that
+    /// any body is UTF-8 encoded JSON.
+    fn read_body(&self, req: &mut Request) -> result::Result<String, BodyError> {
+        if let (Some(max), Some(&ContentLength(length))) =
+            (self.max_body_size, req.headers.get::<ContentLength>()) {
+            // Reject without reading what the client is about to send
+            if length > max as u64 {
+                return Err(BodyError::TooLarge(max));
+            }
+        }
+        let mut body = vec![];
+        match self.max_body_size {
+            // Read one byte past the limit so oversized bodies can be detected
+            Some(max) => req.take(max as u64 + 1).read_to_end(&mut body),
+            None => req.read_to_end(&mut body),
+        }.map_err(BodyError::Io)?;
+        if let Some(max) = self.max_body_size {
+            if body.len() > max {
+                return Err(BodyError::TooLarge(max));
+            }
+        }
+        check_content_type(&req.headers)?;
+        String::from_utf8(body).map_err(|_| BodyError::InvalidUtf8)
+    }
+
+    /// Run the checks every request must pass and read its body.
+    ///
+    /// The body is only read once the request is known to be acceptable.
+    /// On failure this gives the HTTP status to respond with, which need not
+    /// be the usual one for the error.
+    fn accept_request(&self, req: &mut Request, res: &mut Response)
+                      -> result::Result<String, (StatusCode, WebDriverError)> {
+        let rejected = |res: &mut Response, status, err| {
+            // The rest of the body may still be unread
+            res.headers_mut().set(Connection::close());
+            Err((status, err))
+        };
+        if let Err(err) = self.check_request(&req.headers) {
+            return rejected(res, err.http_status(), err);
+        }
+        if let Err(err) = self.check_authentication(&req.headers) {
+            if let Some(ref auth) = self.authentication {
+                res.headers_mut().set_raw("WWW-Authenticate",
+                                          vec![auth.challenge().as_bytes().to_vec()]);
+            }
+            return rejected(res, err.http_status(), err);
+        }
+        match self.read_body(req) {
+            Ok(body) => Ok(body),
+            Err(err) => rejected(res, err.http_status(), err.into()),
+        }
+    }
+
+    fn record(&self,
+              method: &Method,
+              route: Option<&str>,
+              result: result::Result<(), &WebDriverError>,
+              started: Instant) {
+        if let Some(ref metrics) = self.metrics {
+            metrics.record(method, route, result.err().map(|x| &x.error), started.elapsed());
+        }
+    }
+
+    /// Send the response to a request, as JSON.
+    fn respond(&self,
+               mut res: Response,
+               accepts_gzip: bool,
+               status: StatusCode,
+               body: result::Result<WebDriverResponse, String>) {
+        debug!("Returning status {:?}", status);
+        {
+            let resp_status = res.status_mut();
+            *resp_status = status;
+        }
+        res.headers_mut().set(
+            ContentType(Mime(TopLevel::Application, SubLevel::Json,
+                             vec![(Attr::Charset, Value::Utf8)])));
+        res.headers_mut().set(CacheControl(vec![CacheDirective::NoCache]));
+        let writer = match self.compression_threshold {
+            Some(threshold) => {
+                res.headers_mut().set_raw("Vary", vec![b"Accept-Encoding".to_vec()]);
+                BodyWriter::new(res, threshold, accepts_gzip)
+            },
+            None => BodyWriter::new(res, RESPONSE_BUFFER_SIZE, false)
+        };
+        let log_limit = if log_enabled!(LogLevel::Debug) { LOGGED_BODY_SIZE } else { 0 };
+        let mut writer = LoggedWriter::new(writer, log_limit);
+        let written = match body {
+            Ok(response) => response.write_json(&mut writer),
+            Err(err) => writer.write_all(err.as_bytes()),
+        };
+        if log_limit > 0 {
+            let ellipsis = if writer.written > writer.logged.len() { "..." } else { "" };
+            debug!("Returning body {}{} ({} bytes)",
+                   String::from_utf8_lossy(&writer.logged), ellipsis, writer.written);
+        }
+        if let Err(e) = written.and_then(|_| writer.inner.finish()) {
+            error!("Failed to send response: {}", e);
+        }
+    }
+
+    fn check_authentication(&self, headers: &Headers) -> WebDriverResult<()> {
+        match self.authentication {
+            Some(ref auth) if !auth.check(headers) => {
+                Err(WebDriverError::new(ErrorStatus::Unauthorized,
+                                        "Missing or invalid credentials"))
+            },
+            _ => Ok(())
         }
     }
 }
@@ -167,92 +963,2076 @@ impl <U: WebDriverExtensionRoute> Handler for HttpHandler<U> {
     fn handle(&self, req: Request, res: Response) {
         let mut req = req;
         let mut res = res;
+        // Counted before the shutdown flag is checked, so that `shutdown`
+        // either waits for this request or it is rejected
+        let _in_flight = InFlight::new(&self.state);
 
-        let mut body = String::new();
-        if let Method::Post = req.method {
-            req.read_to_string(&mut body).unwrap();
-        }
+        let accepts_gzip = accepts_gzip(&req.headers);
         debug!("Got request {} {:?}", req.method, req.uri);
-        match req.uri {
-            AbsolutePath(path) => {
-                let msg_result = {
-                    // The fact that this locks for basically the whole request doesn't
-                    // matter as long as we are only handling one request at a time.
-                    match self.api.lock() {
-                        Ok(ref api) => {
-                            api.decode_request(req.method, &path[..], &body[..])
-                        },
-                        Err(_) => return
-                    }
-                };
-                let (status, resp_body) = match msg_result {
-                    Ok(message) => {
-                        let (send_res, recv_res) = channel();
-                        match self.chan.lock() {
-                            Ok(ref c) => {
-                                let res = c.send(DispatchMessage::HandleWebDriver(message,
-                                                                                  send_res));
-                                match res {
-                                    Ok(x) => x,
-                                    Err(_) => {
-                                        error!("Something terrible happened");
-                                        return
-                                    }
-                                }
-                            },
-                            Err(_) => {
-                                error!("Something terrible happened");
-                                return
-                            }
-                        }
-                        match recv_res.recv() {
-                            Ok(data) => match data {
-                                Ok(response) => (StatusCode::Ok, response.to_json_string()),
-                                Err(err) => (err.http_status(), err.to_json_string()),
-                            },
-                            Err(e) => panic!("Error reading response: {:?}", e)
-                        }
-                    },
-                    Err(err) => {
-                        (err.http_status(), err.to_json_string())
+        let path = match req.uri {
+            AbsolutePath(ref path) => path.clone(),
+            _ => return
+        };
+        let started = Instant::now();
+        let method = req.method.clone();
+
+        let body = match self.accept_request(&mut req, &mut res) {
+            Ok(body) => body,
+            Err((status, err)) => {
+                self.record(&method, None, Err(&err), started);
+                return self.respond(res, accepts_gzip, status, Err(err.to_json_string()));
+            }
+        };
+        if req.method == Method::Get && self.metrics_path.as_ref() == Some(&path) {
+            return self.send_metrics(res);
+        }
+
+        // The fact that this locks for basically the whole request doesn't
+        // matter as long as we are only handling one request at a time.
+        let (route, msg_result) = match self.api.lock() {
+            Ok(ref api) => {
+                let (matched, msg) = api.decode_request_route(req.method, &path[..], &body[..]);
+                if let Err(ref err) = msg {
+                    if err.error == ErrorStatus::UnknownMethod {
+                        res.headers_mut().set(Allow(api.allowed_methods(&path)));
                     }
-                };
-                debug!("Returning status {:?}", status);
-                debug!("Returning body {}", resp_body);
-                {
-                    let resp_status = res.status_mut();
-                    *resp_status = status;
                 }
-                res.headers_mut().set(
-                    ContentType(Mime(TopLevel::Application, SubLevel::Json,
-                                     vec![(Attr::Charset, Value::Utf8)])));
-                res.headers_mut().set(CacheControl(vec![CacheDirective::NoCache]));
-                res.send(&resp_body.as_bytes()).unwrap();
+                (matched.map(|x| x.to_string()), msg)
             },
-            _ => {}
+            Err(_) => return
+        };
+        let result = msg_result.and_then(|message| self.dispatch(message));
+        self.record(&method, route.as_ref().map(|x| &x[..]), result.as_ref().map(|_| ()), started);
+        let (status, resp_body) = match result {
+            Ok(response) => (StatusCode::Ok, Ok(response)),
+            Err(err) => (err.http_status(), Err(err.to_json_string())),
+        };
+        self.respond(res, accepts_gzip, status, resp_body);
+    }
+}
+
+/// Reasons for rejecting a request body.
+#[derive(Debug)]
+enum BodyError {
+    /// The body is larger than the maximum size, in bytes.
+    TooLarge(usize),
+    InvalidUtf8,
+    /// The body is declared as something other than JSON; holds the given
+    /// `Content-Type`.
+    UnsupportedContentType(String),
+    Io(io::Error),
+}
+
+impl BodyError {
+    fn http_status(&self) -> StatusCode {
+        match *self {
+            BodyError::TooLarge(_) => StatusCode::PayloadTooLarge,
+            BodyError::UnsupportedContentType(_) => StatusCode::UnsupportedMediaType,
+            BodyError::InvalidUtf8 | BodyError::Io(_) => StatusCode::BadRequest,
         }
     }
 }
 
-pub fn start<T, U>(address: SocketAddr,
-                   handler: T,
-                   extension_routes: &[(Method, &str, U)])
-                   -> Result<Listening>
-    where T: 'static + WebDriverHandler<U>,
-          U: 'static + WebDriverExtensionRoute
-{
-    let (msg_send, msg_recv) = channel();
+impl From<BodyError> for WebDriverError {
+    fn from(err: BodyError) -> WebDriverError {
+        let message = match err {
+            BodyError::TooLarge(max) => {
+                format!("Request body exceeds the maximum size of {} bytes", max)
+            },
+            BodyError::InvalidUtf8 => "Request body is not valid UTF-8".to_string(),
+            BodyError::UnsupportedContentType(content_type) => {
+                format!("Unsupported Content-Type {}, expected application/json", content_type)
+            },
+            BodyError::Io(err) => format!("Failed to read request body: {}", err),
+        };
+        WebDriverError::new(ErrorStatus::InvalidArgument, message)
+    }
+}
 
-    let api = WebDriverHttpApi::new(extension_routes);
-    let http_handler = HttpHandler::new(api, msg_send);
-    let mut server = try!(Server::http(address));
-    server.keep_alive(None);
+/// Check that a request body isn't declared as something other than UTF-8
+/// encoded JSON.
+///
+/// Requests without a `Content-Type` are accepted, as many clients don't
+/// send one. Other types are rejected even when the body is JSON, as the
+/// ones a browser page can send without a CORS preflight, such as
+/// `text/plain`, would otherwise get past the `Origin` check.
+fn check_content_type(headers: &Headers) -> result::Result<(), BodyError> {
+    let raw = match headers.get_raw("Content-Type") {
+        Some(raw) => raw,
+        None => return Ok(())
+    };
+    let unsupported = || {
+        let value = raw.iter()
+            .map(|x| String::from_utf8_lossy(x).into_owned())
+            .collect::<Vec<_>>()
+            .join(", ");
+        BodyError::UnsupportedContentType(value)
+    };
+    let &ContentType(Mime(ref top, ref sub, ref params)) = match headers.get::<ContentType>() {
+        Some(content_type) => content_type,
+        None => return Err(unsupported())
+    };
+    let utf8 = params.iter().all(|&(ref attr, ref value)| {
+        *attr != Attr::Charset || *value == Value::Utf8
+    });
+    match (top, sub) {
+        (&TopLevel::Application, &SubLevel::Json) if utf8 => Ok(()),
+        _ => Err(unsupported())
+    }
+}
+
+/// Whether the client accepts gzip compressed responses, according to its
+/// `Accept-Encoding` header.
+fn accepts_gzip(headers: &Headers) -> bool {
+    let encodings = match headers.get::<AcceptEncoding>() {
+        Some(&AcceptEncoding(ref encodings)) => encodings,
+        None => return false
+    };
+    let quality = |encoding: &Encoding| {
+        encodings.iter().find(|x| x.item == *encoding).map(|x| x.quality.0)
+    };
+    match quality(&Encoding::Gzip) {
+        Some(quality) => quality > 0,
+        None => quality(&Encoding::EncodingExt("*".into())).map_or(false, |x| x > 0),
+    }
+}
+
+/// Writer for response bodies.
+///
+/// The start of the body is held back until it reaches `threshold` bytes.
+/// Bodies that stay smaller are sent as is, with a `Content-Length`. Larger
+/// ones are streamed, and gzip compressed if the client accepts that.
+enum BodyWriter<'a> {
+    Buffering {
+        res: Option<Response<'a>>,
+        buf: Vec<u8>,
+        threshold: usize,
+        gzip: bool,
+    },
+    Plain(BufWriter<Response<'a, Streaming>>),
+    Gzip(GzEncoder<BufWriter<Response<'a, Streaming>>>),
+}
+
+impl<'a> BodyWriter<'a> {
+    fn new(res: Response<'a>, threshold: usize, gzip: bool) -> BodyWriter<'a> {
+        BodyWriter::Buffering {
+            res: Some(res),
+            buf: Vec::with_capacity(cmp::min(threshold, RESPONSE_BUFFER_SIZE)),
+            threshold: threshold,
+            gzip: gzip,
+        }
+    }
+
+    /// Start streaming the response, once the buffered body has reached
+    /// the threshold.
+    fn start(&mut self) -> io::Result<()> {
+        let (res, buf, gzip) = match *self {
+            BodyWriter::Buffering { ref mut res, ref mut buf, gzip, .. } => {
+                (res.take().unwrap(), mem::replace(buf, vec![]), gzip)
+            },
+            _ => return Ok(())
+        };
+        let mut res = res;
+        if gzip {
+            res.headers_mut().set(ContentEncoding(vec![Encoding::Gzip]));
+        }
+        let res = BufWriter::with_capacity(RESPONSE_BUFFER_SIZE, res.start()?);
+        *self = if gzip {
+            BodyWriter::Gzip(GzEncoder::new(res, Compression::default()))
+        } else {
+            BodyWriter::Plain(res)
+        };
+        self.write_all(&buf)
+    }
+
+    /// Send whatever remains of the response.
+    fn finish(self) -> io::Result<()> {
+        match self {
+            BodyWriter::Buffering { mut res, buf, .. } => res.take().unwrap().send(&buf),
+            BodyWriter::Plain(res) => res.into_inner().map_err(|e| e.into_error())?.end(),
+            BodyWriter::Gzip(encoder) => {
+                encoder.finish()?.into_inner().map_err(|e| e.into_error())?.end()
+            }
+        }
+    }
+}
+
+impl<'a> Write for BodyWriter<'a> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let full = match *self {
+            BodyWriter::Buffering { ref mut buf, threshold, .. } => {
+                buf.extend_from_slice(data);
+                buf.len() >= threshold
+            },
+            BodyWriter::Plain(ref mut res) => return res.write(data),
+            BodyWriter::Gzip(ref mut encoder) => return encoder.write(data),
+        };
+        if full {
+            self.start()?;
+        }
+        Ok(data.len())
+    }
 
-    let builder = thread::Builder::new().name("webdriver dispatcher".to_string());
-    try!(builder.spawn(move || {
-        let mut dispatcher = Dispatcher::new(handler);
-        dispatcher.run(msg_recv);
-    }));
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            BodyWriter::Buffering { .. } => Ok(()),
+            BodyWriter::Plain(ref mut res) => res.flush(),
+            BodyWriter::Gzip(ref mut encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Writer that keeps the start of what is written to `inner`, for the log.
+struct LoggedWriter<W> {
+    inner: W,
+    /// Up to `limit` bytes from the start
+    logged: Vec<u8>,
+    limit: usize,
+    /// Total number of bytes written
+    written: usize,
+}
 
-    server.handle(http_handler)
+impl<W: Write> LoggedWriter<W> {
+    fn new(inner: W, limit: usize) -> LoggedWriter<W> {
+        LoggedWriter {
+            inner: inner,
+            logged: vec![],
+            limit: limit,
+            written: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for LoggedWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(data)?;
+        let keep = cmp::min(len, self.limit - self.logged.len());
+        self.logged.extend_from_slice(&data[..keep]);
+        self.written += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Listener that stops accepting connections once the server is shutting
+/// down, and lets the socket be closed.
+///
+/// hyper's acceptor threads never exit, and `Listening::close` leaves them
+/// running. Instead each clone of this listener, one per acceptor thread,
+/// takes its own handle to the socket from a shared slot. On shutdown the
+/// slot is emptied and the threads are woken with a connection, upon which
+/// they drop their handle and go idle, so the socket gets closed.
+struct ShutdownListener<L: NetworkListener> {
+    shared: Arc<SharedListener<L>>,
+    local: Option<L>,
+}
+
+struct SharedListener<L> {
+    listener: Mutex<Option<L>>,
+    /// Number of acceptor threads holding a handle to the socket.
+    acceptors: AtomicUsize,
+    shutdown: Arc<AtomicBool>,
+}
+
+/// Control over the acceptor threads of a listener, for `ServerHandle`.
+trait CloseListener: Send + Sync {
+    /// Stop handing out the socket to acceptor threads.
+    fn close(&self);
+
+    /// Number of acceptor threads that still hold the socket open.
+    fn acceptors(&self) -> usize;
+}
+
+impl<L: NetworkListener + Send> CloseListener for SharedListener<L> {
+    fn close(&self) {
+        if let Ok(mut listener) = self.listener.lock() {
+            listener.take();
+        }
+    }
+
+    fn acceptors(&self) -> usize {
+        self.acceptors.load(Ordering::SeqCst)
+    }
+}
+
+impl<L: NetworkListener> ShutdownListener<L> {
+    fn new(listener: L, shutdown: Arc<AtomicBool>) -> ShutdownListener<L> {
+        ShutdownListener {
+            shared: Arc::new(SharedListener {
+                listener: Mutex::new(Some(listener)),
+                acceptors: AtomicUsize::new(0),
+                shutdown: shutdown,
+            }),
+            local: None,
+        }
+    }
+
+    /// This thread's handle to the socket, or `None` once the listener is
+    /// closed.
+    fn local(&mut self) -> Option<&mut L> {
+        if self.local.is_none() {
+            if let Ok(listener) = self.shared.listener.lock() {
+                if let Some(ref listener) = *listener {
+                    self.shared.acceptors.fetch_add(1, Ordering::SeqCst);
+                    self.local = Some(listener.clone());
+                }
+            }
+        }
+        self.local.as_mut()
+    }
+
+    /// Apply a setting to this thread's handle and to the one that later
+    /// threads get theirs from.
+    fn configure<F: Fn(&mut L)>(&mut self, f: F) {
+        if let Some(ref mut listener) = self.local {
+            f(listener);
+        }
+        if let Ok(mut listener) = self.shared.listener.lock() {
+            if let Some(ref mut listener) = *listener {
+                f(listener);
+            }
+        }
+    }
+}
+
+impl<L: NetworkListener> Clone for ShutdownListener<L> {
+    fn clone(&self) -> ShutdownListener<L> {
+        ShutdownListener {
+            shared: self.shared.clone(),
+            local: None,
+        }
+    }
+}
+
+impl<L: NetworkListener> NetworkListener for ShutdownListener<L> {
+    type Stream = L::Stream;
+
+    fn accept(&mut self) -> Result<L::Stream> {
+        loop {
+            let result = match self.local() {
+                Some(listener) => listener.accept(),
+                None => loop {
+                    // hyper restarts acceptor threads that exit, so stay idle
+                    // for good instead
+                    thread::park();
+                }
+            };
+            if !self.shared.shutdown.load(Ordering::SeqCst) {
+                return result;
+            }
+            debug!("Closing listener during shutdown");
+            self.local = None;
+            self.shared.acceptors.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        if let Some(ref mut listener) = self.local {
+            return listener.local_addr();
+        }
+        match self.shared.listener.lock() {
+            Ok(mut listener) => match *listener {
+                Some(ref mut listener) => listener.local_addr(),
+                None => Err(io::Error::new(io::ErrorKind::NotConnected, "Listener is closed"))
+            },
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Listener lock poisoned"))
+        }
+    }
+
+    fn set_read_timeout(&mut self, dur: Option<Duration>) {
+        self.configure(|listener| listener.set_read_timeout(dur))
+    }
+
+    fn set_write_timeout(&mut self, dur: Option<Duration>) {
+        self.configure(|listener| listener.set_write_timeout(dur))
+    }
+}
+
+/// `HttpHandler` shared between the listeners for each address.
+struct SharedHandler<U: WebDriverExtensionRoute>(Arc<HttpHandler<U>>);
+
+impl<U: WebDriverExtensionRoute> Handler for SharedHandler<U> {
+    fn handle<'a, 'k>(&'a self, req: Request<'a, 'k>, res: Response<'a>) {
+        self.0.handle(req, res)
+    }
+}
+
+/// Address for the server to listen on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    /// Path of a Unix domain socket. Access to the server can then be
+    /// restricted using the permissions of the socket file or its directory.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Handle to a running WebDriver server.
+///
+/// Dropping the handle blocks for as long as the server keeps listening, in
+/// the same way as hyper's `Listening`. Use [`shutdown`] to stop the server
+/// and clean up any live session.
+///
+/// [`shutdown`]: #method.shutdown
+pub struct ServerHandle<U: WebDriverExtensionRoute> {
+    addresses: Vec<ListenAddress>,
+    listening: Vec<Listening>,
+    /// The listener for each of `addresses`.
+    listeners: Vec<Arc<dyn CloseListener>>,
+    shutdown: Arc<AtomicBool>,
+    state: Arc<ServerState>,
+    chan: Sender<DispatchMessage<U>>,
+    dispatcher: JoinHandle<()>,
+    dispatcher_done: Receiver<()>,
+}
+
+impl<U: WebDriverExtensionRoute> ServerHandle<U> {
+    /// The first address the server is listening on.
+    ///
+    /// For TCP addresses this has the port that was actually bound, so the
+    /// server can be started on port 0 and clients pointed at the port that
+    /// the operating system picked.
+    pub fn address(&self) -> &ListenAddress {
+        &self.addresses[0]
+    }
+
+    /// All the addresses the server is listening on, in the order they
+    /// were configured.
+    pub fn addresses(&self) -> &[ListenAddress] {
+        &self.addresses
+    }
+
+    /// The first TCP address the server is listening on, or `None` when it
+    /// is only listening on Unix domain sockets.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.addresses.iter().filter_map(|address| match *address {
+            ListenAddress::Tcp(addr) => Some(addr),
+            #[cfg(unix)]
+            ListenAddress::Unix(_) => None,
+        }).next()
+    }
+
+    /// Stop the server.
+    ///
+    /// New connections and requests are rejected straight away. Requests
+    /// that were already being handled are allowed to complete and get
+    /// their response, after which any live session is deleted through
+    /// `WebDriverHandler::delete_session` and the dispatcher thread is
+    /// joined. If this does not happen within `timeout` a `Timeout` error is
+    /// returned and the dispatcher thread is left to finish on its own.
+    ///
+    /// The listening sockets are closed, so the addresses can be bound
+    /// again, and Unix domain socket files are removed. hyper's connection
+    /// threads can't be stopped, but they are left idle. A thread still
+    /// serving a kept-alive connection when `timeout` expires keeps its
+    /// socket open until that connection ends.
+    pub fn shutdown(mut self, timeout: Duration) -> WebDriverResult<()> {
+        debug!("Shutting down server");
+        let deadline = Instant::now() + timeout;
+        self.shutdown.store(true, Ordering::SeqCst);
+        for listening in self.listening.iter_mut() {
+            listening.close().map_err(|e| {
+                WebDriverError::new(ErrorStatus::UnknownError, e.to_string())
+            })?;
+        }
+
+        while self.state.requests.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        if self.state.requests.load(Ordering::SeqCst) > 0 {
+            warn!("Stopping the dispatcher with requests still in flight");
+        }
+
+        if self.chan.send(DispatchMessage::Quit).is_err() {
+            // The dispatcher has already gone away, so there is nothing left
+            // to wait for.
+            error!("Dispatcher exited before shutdown");
+        }
+
+        for (address, listener) in self.addresses.iter().zip(self.listeners.iter()) {
+            close_listener(address, &**listener, deadline);
+        }
+        remove_sockets(&self.addresses);
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match self.dispatcher_done.recv_timeout(remaining) {
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => {
+                if self.dispatcher.join().is_err() {
+                    return Err(WebDriverError::new(ErrorStatus::UnknownError,
+                                                   "Dispatcher thread panicked"));
+                }
+                Ok(())
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                Err(WebDriverError::new(ErrorStatus::Timeout,
+                                        "Timed out waiting for in-flight commands to finish"))
+            }
+        }
+    }
+}
+
+/// How long to wait for the listeners to close when starting a server fails.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Close `listener`, connecting to `address` to wake its acceptor threads
+/// until they have all let go of the socket or `deadline` passes.
+fn close_listener(address: &ListenAddress, listener: &dyn CloseListener, deadline: Instant) {
+    listener.close();
+    while listener.acceptors() > 0 && Instant::now() < deadline {
+        if let Err(e) = connect(address) {
+            debug!("Failed to wake acceptor threads: {}", e);
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    if listener.acceptors() > 0 {
+        warn!("{} connection threads are still busy; their sockets close once they are done",
+              listener.acceptors());
+    }
+}
+
+/// Open and immediately drop a connection to `address`.
+fn connect(address: &ListenAddress) -> io::Result<()> {
+    match *address {
+        ListenAddress::Tcp(mut addr) => {
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr.ip() {
+                    IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                });
+            }
+            TcpStream::connect_timeout(&addr, Duration::from_millis(100)).map(|_| ())
+        },
+        #[cfg(unix)]
+        ListenAddress::Unix(ref path) => UnixStream::connect(path).map(|_| ()),
+    }
+}
+
+/// Whether binding failed because the host doesn't support the address or
+/// its address family, e.g. because IPv6 is disabled.
+fn address_unavailable(err: &io::Error) -> bool {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const EAFNOSUPPORT: i32 = 97;
+    #[cfg(windows)]
+    const EAFNOSUPPORT: i32 = 10047;
+    #[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+    const EAFNOSUPPORT: i32 = 47;
+    err.kind() == io::ErrorKind::AddrNotAvailable || err.raw_os_error() == Some(EAFNOSUPPORT)
+}
+
+/// Remove the socket files of any Unix domain socket addresses.
+fn remove_sockets(addresses: &[ListenAddress]) {
+    #[cfg(unix)]
+    {
+        for address in addresses {
+            if let ListenAddress::Unix(ref path) = *address {
+                if let Err(e) = ::std::fs::remove_file(path) {
+                    warn!("Failed to remove socket {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+/// Builder for configuring and starting a WebDriver server.
+///
+/// ```no_run
+/// # use webdriver::server::{ServerBuilder, WebDriverHandler};
+/// # use webdriver::httpapi::VoidWebDriverExtensionRoute;
+/// # fn run<T: 'static + WebDriverHandler>(handler: T) {
+/// let server = ServerBuilder::<VoidWebDriverExtensionRoute>::new("127.0.0.1:4444".parse().unwrap())
+///     .threads(4)
+///     .start(handler)
+///     .unwrap();
+/// # }
+/// ```
+pub struct ServerBuilder<U: WebDriverExtensionRoute=VoidWebDriverExtensionRoute> {
+    addresses: Vec<ListenAddress>,
+    /// Addresses that are skipped, rather than failing `start`, if the host
+    /// doesn't support them.
+    optional_addresses: Vec<ListenAddress>,
+    extension_routes: Vec<(Method, String, U)>,
+    threads: Option<usize>,
+    keep_alive: Option<Duration>,
+    max_body_size: Option<usize>,
+    compression_threshold: Option<usize>,
+    allowed_hosts: Vec<String>,
+    allowed_origins: Vec<String>,
+    url_prefix: Option<String>,
+    refuse_new_sessions: bool,
+    deadline_margin: Option<Duration>,
+    command_deadline: Option<Box<CommandDeadline<U>>>,
+    middleware: Vec<Box<dyn CommandMiddleware<U>>>,
+    metrics_path: Option<String>,
+    authentication: Option<Authentication>,
+    #[cfg(feature = "tls")]
+    tls: Option<(PathBuf, PathBuf)>,
+    #[cfg(feature = "tls")]
+    tls_client_ca: Option<PathBuf>,
+}
+
+impl<U: 'static + WebDriverExtensionRoute> ServerBuilder<U> {
+    pub fn new(address: SocketAddr) -> ServerBuilder<U> {
+        ServerBuilder::listen(ListenAddress::Tcp(address))
+    }
+
+    /// Serve on a Unix domain socket at `path` instead of a TCP port.
+    ///
+    /// The socket file must not already exist; it is removed again by
+    /// [`ServerHandle::shutdown`]. Clients still need to send a `Host`
+    /// header that passes the [`allowed_hosts`] check, e.g. `localhost`.
+    ///
+    /// [`ServerHandle::shutdown`]: struct.ServerHandle.html#method.shutdown
+    /// [`allowed_hosts`]: #method.allowed_hosts
+    #[cfg(unix)]
+    pub fn unix<P: Into<PathBuf>>(path: P) -> ServerBuilder<U> {
+        ServerBuilder::listen(ListenAddress::Unix(path.into()))
+    }
+
+    /// Serve on both the IPv4 and IPv6 loopback addresses, so that clients
+    /// can reach the server as `localhost` however they resolve it.
+    ///
+    /// With a `port` of 0 a free port is picked and shared by both
+    /// addresses. On hosts without IPv6 only the IPv4 address is served.
+    pub fn localhost(port: u16) -> ServerBuilder<U> {
+        let ipv6 = ListenAddress::Tcp(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), port));
+        let mut builder = ServerBuilder::new(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port))
+            .add_address(ipv6.clone());
+        builder.optional_addresses.push(ipv6);
+        builder
+    }
+
+    /// Serve on `address`.
+    pub fn listen(address: ListenAddress) -> ServerBuilder<U> {
+        ServerBuilder {
+            addresses: vec![address],
+            optional_addresses: vec![],
+            extension_routes: vec![],
+            threads: None,
+            keep_alive: None,
+            max_body_size: Some(DEFAULT_MAX_BODY_SIZE),
+            compression_threshold: Some(DEFAULT_COMPRESSION_THRESHOLD),
+            allowed_hosts: default_allowed_hosts(),
+            allowed_origins: vec![],
+            url_prefix: None,
+            refuse_new_sessions: false,
+            deadline_margin: None,
+            command_deadline: None,
+            middleware: vec![],
+            metrics_path: None,
+            authentication: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            tls_client_ca: None,
+        }
+    }
+
+    /// Serve on `address` as well as the addresses configured so far.
+    ///
+    /// TCP addresses with port 0 all get the same port, picked when the
+    /// first of them is bound. Each address has its own pool of
+    /// [`threads`].
+    ///
+    /// [`threads`]: #method.threads
+    pub fn add_address(mut self, address: ListenAddress) -> ServerBuilder<U> {
+        self.addresses.push(address);
+        self
+    }
+
+    /// Routes for vendor-specific commands, in addition to the standard ones.
+    pub fn extension_routes(mut self, routes: &[(Method, &str, U)]) -> ServerBuilder<U> {
+        self.extension_routes = routes
+            .iter()
+            .map(|&(ref method, path, ref route)| (method.clone(), path.to_string(), route.clone()))
+            .collect();
+        self
+    }
+
+    /// Number of threads accepting HTTP connections on each address.
+    ///
+    /// Defaults to hyper's choice, which depends on the number of CPUs.
+    pub fn threads(mut self, threads: usize) -> ServerBuilder<U> {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// How long to keep idle connections open, or `None` to close each
+    /// connection after a single request. Defaults to `None`.
+    pub fn keep_alive(mut self, timeout: Option<Duration>) -> ServerBuilder<U> {
+        self.keep_alive = timeout;
+        self
+    }
+
+    /// Maximum size in bytes of a request body, or `None` for no limit.
+    ///
+    /// Larger requests are rejected with `413 Payload Too Large`. Defaults
+    /// to 64 MiB.
+    pub fn max_body_size<T: Into<Option<usize>>>(mut self, max: T) -> ServerBuilder<U> {
+        self.max_body_size = max.into();
+        self
+    }
+
+    /// Size in bytes from which responses are gzip compressed for clients
+    /// that send `Accept-Encoding: gzip`, or `None` to never compress.
+    ///
+    /// This mostly benefits page source and screenshot responses sent to
+    /// remote clients. Defaults to 16 KiB.
+    pub fn compression_threshold<T: Into<Option<usize>>>(mut self, threshold: T)
+                                                         -> ServerBuilder<U> {
+        self.compression_threshold = threshold.into();
+        self
+    }
+
+    /// Host names that requests may be addressed to, as given in the `Host`
+    /// header, in addition to loopback IP addresses.
+    ///
+    /// This protects against DNS rebinding attacks from web content.
+    /// Defaults to `localhost`. When listening on a non-loopback interface,
+    /// the names or IP addresses that clients use to reach the server need to
+    /// be added here.
+    pub fn allowed_hosts(mut self, hosts: Vec<String>) -> ServerBuilder<U> {
+        self.allowed_hosts = hosts;
+        self
+    }
+
+    /// Values of the `Origin` header that are accepted, e.g.
+    /// `http://localhost:8000`.
+    ///
+    /// Browsers send an `Origin` header with requests made by web content,
+    /// so by default any request with this header is rejected.
+    pub fn allowed_origins(mut self, origins: Vec<String>) -> ServerBuilder<U> {
+        self.allowed_origins = origins;
+        self
+    }
+
+    /// Path under which all the WebDriver endpoints are served, e.g. `/wd/hub`.
+    ///
+    /// See [`WebDriverHttpApi::with_prefix`].
+    ///
+    /// [`WebDriverHttpApi::with_prefix`]: ../httpapi/struct.WebDriverHttpApi.html#method.with_prefix
+    pub fn url_prefix<S: Into<String>>(mut self, prefix: S) -> ServerBuilder<U> {
+        self.url_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Refuse to create sessions, failing `NewSession` commands with
+    /// `SessionNotCreated` and reporting the server as not ready.
+    ///
+    /// The server only runs one session at a time in any case, so there is
+    /// no limit on the number of sessions to configure.
+    pub fn refuse_new_sessions(mut self, refuse: bool) -> ServerBuilder<U> {
+        self.refuse_new_sessions = refuse;
+        self
+    }
+
+    /// Give up waiting for a command once the relevant session timeout plus
+    /// `margin` has passed since the dispatcher started on it.
+    ///
+    /// Navigation commands are bounded by the page load timeout, scripts by
+    /// the script timeout and element lookups by the implicit wait timeout.
+    /// Other commands have no bound unless `command_deadline` gives one.
+    /// When a deadline passes the client gets a `Timeout` error, further
+    /// commands fail straight away rather than queueing behind the stuck
+    /// one, and the session is deleted once the handler eventually returns.
+    pub fn command_deadline_margin(mut self, margin: Duration) -> ServerBuilder<U> {
+        self.deadline_margin = Some(margin);
+        self
+    }
+
+    /// Fixed deadlines for particular commands, counted from when the
+    /// dispatcher starts on the command.
+    ///
+    /// Where `deadline` returns `None` the deadline is derived from the
+    /// session timeouts as described in [`command_deadline_margin`], if set.
+    ///
+    /// [`command_deadline_margin`]: #method.command_deadline_margin
+    pub fn command_deadline<F>(mut self, deadline: F) -> ServerBuilder<U>
+        where F: 'static + Fn(&WebDriverCommand<U::Command>) -> Option<Duration> + Send + Sync
+    {
+        self.command_deadline = Some(Box::new(deadline));
+        self
+    }
+
+    /// Add a middleware to run around every command.
+    ///
+    /// See [`CommandMiddleware`] for the order in which middleware runs.
+    ///
+    /// [`CommandMiddleware`]: trait.CommandMiddleware.html
+    pub fn middleware<M>(mut self, middleware: M) -> ServerBuilder<U>
+        where M: 'static + CommandMiddleware<U>
+    {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Collect request metrics and serve them in the Prometheus text format
+    /// on `path`, e.g. `/metrics`.
+    ///
+    /// The path is matched exactly, ignoring any [`url_prefix`].
+    ///
+    /// [`url_prefix`]: #method.url_prefix
+    pub fn metrics_path<S: Into<String>>(mut self, path: S) -> ServerBuilder<U> {
+        self.metrics_path = Some(path.into());
+        self
+    }
+
+    /// Require clients to authenticate with the given credentials.
+    ///
+    /// This applies to every request, including the status and metrics
+    /// endpoints. Requests without valid credentials get a 401 response with
+    /// a `WWW-Authenticate` challenge and the body
+    ///
+    /// ```text
+    /// {"value": {"error": "unauthorized",
+    ///            "message": "Missing or invalid credentials",
+    ///            "stacktrace": ...}}
+    /// ```
+    ///
+    /// Credentials are sent in the clear unless the connection is otherwise
+    /// protected.
+    pub fn authentication(mut self, authentication: Authentication) -> ServerBuilder<U> {
+        self.authentication = Some(authentication);
+        self
+    }
+
+    /// Serve HTTPS using the PEM encoded certificate chain and private key
+    /// in the given files.
+    ///
+    /// This is only supported when listening on a TCP address.
+    #[cfg(feature = "tls")]
+    pub fn tls<P, Q>(mut self, certificate: P, private_key: Q) -> ServerBuilder<U>
+        where P: Into<PathBuf>,
+              Q: Into<PathBuf>
+    {
+        self.tls = Some((certificate.into(), private_key.into()));
+        self
+    }
+
+    /// Require HTTPS clients to present a certificate signed by one of the
+    /// PEM encoded CA certificates in `path`.
+    ///
+    /// Connections without an acceptable certificate are dropped during the
+    /// TLS handshake. Has no effect unless [`tls`] is also set.
+    ///
+    /// [`tls`]: #method.tls
+    #[cfg(feature = "tls")]
+    pub fn tls_client_ca<P: Into<PathBuf>>(mut self, path: P) -> ServerBuilder<U> {
+        self.tls_client_ca = Some(path.into());
+        self
+    }
+
+    /// Bind the configured address and start serving with `handler`.
+    pub fn start<T>(self, handler: T) -> Result<ServerHandle<U>>
+        where T: 'static + WebDriverHandler<U>
+    {
+        let extension_routes = self.extension_routes
+            .iter()
+            .map(|&(ref method, ref path, ref route)| (method.clone(), &path[..], route.clone()))
+            .collect::<Vec<_>>();
+        let mut api = WebDriverHttpApi::try_new(&extension_routes).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidInput, err.message.into_owned())
+        })?;
+        if let Some(ref prefix) = self.url_prefix {
+            api = api.with_prefix(prefix);
+        }
+
+        let listeners = self.bind()?;
+
+        let (msg_send, msg_recv) = channel();
+        let (done_send, done_recv) = channel();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let mut state = ServerState::new();
+        state.refuse_new_sessions = self.refuse_new_sessions;
+        let state = Arc::new(state);
+
+        let mut http_handler = HttpHandler::new(api, msg_send.clone(), shutdown.clone(),
+                                                state.clone());
+        http_handler.max_body_size = self.max_body_size;
+        http_handler.compression_threshold = self.compression_threshold;
+        http_handler.allowed_hosts = self.allowed_hosts;
+        http_handler.allowed_origins = self.allowed_origins;
+        http_handler.deadline_margin = self.deadline_margin;
+        http_handler.command_deadline = self.command_deadline;
+        if self.metrics_path.is_some() {
+            http_handler.metrics = Some(Metrics::new());
+        }
+        http_handler.metrics_path = self.metrics_path;
+        http_handler.authentication = self.authentication;
+
+        let middleware = self.middleware;
+        let handle_state = state.clone();
+        let builder = thread::Builder::new().name("webdriver dispatcher".to_string());
+        let dispatcher = builder.spawn(move || {
+            let mut dispatcher = Dispatcher::new(handler, state);
+            dispatcher.middleware = middleware;
+            dispatcher.run(msg_recv);
+            let _ = done_send.send(());
+        })?;
+
+        let http_handler = Arc::new(http_handler);
+        let mut addresses = Vec::with_capacity(listeners.len());
+        let mut listening = Vec::with_capacity(listeners.len());
+        let mut closers = Vec::with_capacity(listeners.len());
+        let mut listeners = listeners.into_iter();
+        while let Some((address, listener)) = listeners.next() {
+            let handler = SharedHandler(http_handler.clone());
+            let result = match listener {
+                Listener::Tcp(listener) => {
+                    serve(listener, shutdown.clone(), self.keep_alive, self.threads, handler)
+                },
+                #[cfg(feature = "tls")]
+                Listener::Https(listener) => {
+                    serve(listener, shutdown.clone(), self.keep_alive, self.threads, handler)
+                },
+                #[cfg(unix)]
+                Listener::Unix(listener) => {
+                    serve(listener, shutdown.clone(), self.keep_alive, self.threads, handler)
+                },
+            };
+            match result {
+                Ok((x, closer)) => {
+                    listening.push(x);
+                    closers.push(closer);
+                },
+                Err(e) => {
+                    shutdown.store(true, Ordering::SeqCst);
+                    for mut x in listening {
+                        let _ = x.close();
+                    }
+                    for (address, closer) in addresses.iter().zip(closers.iter()) {
+                        close_listener(address, &**closer, Instant::now() + CLOSE_TIMEOUT);
+                    }
+                    let _ = msg_send.send(DispatchMessage::Quit);
+                    addresses.push(address);
+                    // Including those bound but not yet served
+                    addresses.extend(listeners.map(|(address, _)| address));
+                    remove_sockets(&addresses);
+                    return Err(e);
+                }
+            }
+            addresses.push(address);
+        }
+
+        Ok(ServerHandle {
+            addresses: addresses,
+            listening: listening,
+            listeners: closers,
+            shutdown: shutdown,
+            state: handle_state,
+            chan: msg_send,
+            dispatcher: dispatcher,
+            dispatcher_done: done_recv,
+        })
+    }
+
+    /// Bind all the configured addresses, returning each along with the
+    /// address that was actually bound.
+    fn bind(&self) -> Result<Vec<(ListenAddress, Listener)>> {
+        #[cfg(feature = "tls")]
+        let tls = match self.tls {
+            Some((ref certificate, ref private_key)) => {
+                let client_ca = self.tls_client_ca.as_ref().map(|x| x.as_path());
+                Some(TlsServer::new(certificate, private_key, client_ca)?)
+            },
+            None => None
+        };
+
+        bind_addresses(&self.addresses, &self.optional_addresses, |address| match *address {
+            ListenAddress::Tcp(addr) => {
+                HttpListener::new(addr).and_then(|mut listener| {
+                    let local_addr = listener.local_addr()?;
+                    #[cfg(feature = "tls")]
+                    let listener = match tls {
+                        Some(ref tls) => {
+                            Listener::Https(HttpsListener::with_listener(listener, tls.clone()))
+                        },
+                        None => Listener::Tcp(listener),
+                    };
+                    #[cfg(not(feature = "tls"))]
+                    let listener = Listener::Tcp(listener);
+                    Ok((ListenAddress::Tcp(local_addr), listener))
+                })
+            },
+            #[cfg(unix)]
+            ListenAddress::Unix(ref path) => {
+                #[cfg(feature = "tls")]
+                let result = match tls {
+                    Some(_) => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                  "TLS requires a TCP address").into()),
+                    None => UnixHttpListener::bind(path),
+                };
+                #[cfg(not(feature = "tls"))]
+                let result = UnixHttpListener::bind(path);
+                result.map(|listener| (address.clone(), Listener::Unix(listener)))
+                    .map_err(|e| e.into())
+            },
+        })
+    }
+}
+
+/// How often to try binding addresses that share a picked port.
+const BIND_ATTEMPTS: usize = 10;
+
+/// Bind each of `addresses` with `bind`, which returns the address that was
+/// actually bound along with the listener.
+///
+/// TCP addresses with port 0 all get the port picked for the first of them.
+/// If that port is taken for one of the later ones, everything is unbound
+/// and tried again with a fresh port. Addresses in `optional` are skipped if
+/// the host doesn't support them.
+fn bind_addresses<T, F>(addresses: &[ListenAddress], optional: &[ListenAddress], mut bind: F)
+                        -> Result<Vec<(ListenAddress, T)>>
+    where F: FnMut(&ListenAddress) -> Result<(ListenAddress, T)>
+{
+    let mut attempt = 0;
+    'attempts: loop {
+        attempt += 1;
+        let mut listeners = Vec::with_capacity(addresses.len());
+        let mut ephemeral_port = None;
+        for address in addresses.iter() {
+            let mut target = address.clone();
+            let mut shared_port = false;
+            if let ListenAddress::Tcp(ref mut addr) = target {
+                if let (0, Some(port)) = (addr.port(), ephemeral_port) {
+                    addr.set_port(port);
+                    shared_port = true;
+                }
+            }
+            match bind(&target) {
+                Ok((bound, listener)) => {
+                    if let (&ListenAddress::Tcp(addr), &ListenAddress::Tcp(bound_addr)) =
+                        (address, &bound) {
+                        if addr.port() == 0 && ephemeral_port.is_none() {
+                            ephemeral_port = Some(bound_addr.port());
+                        }
+                    }
+                    listeners.push((bound, listener));
+                },
+                Err(::hyper::Error::Io(ref e)) if address_unavailable(e) &&
+                    optional.contains(address) => {
+                    warn!("Not listening on {:?}: {}", address, e);
+                },
+                Err(::hyper::Error::Io(ref e)) if shared_port &&
+                    e.kind() == io::ErrorKind::AddrInUse && attempt < BIND_ATTEMPTS => {
+                    debug!("Picked port is in use for {:?}, trying another one", target);
+                    unbind(listeners);
+                    continue 'attempts;
+                },
+                Err(e) => {
+                    unbind(listeners);
+                    return Err(e);
+                }
+            }
+        }
+        return Ok(listeners);
+    }
+}
+
+/// Drop listeners that were bound but won't be served.
+fn unbind<T>(listeners: Vec<(ListenAddress, T)>) {
+    let bound = listeners.into_iter().map(|(address, _)| address).collect::<Vec<_>>();
+    remove_sockets(&bound);
+}
+
+enum Listener {
+    Tcp(HttpListener),
+    #[cfg(feature = "tls")]
+    Https(HttpsListener<TlsServer>),
+    #[cfg(unix)]
+    Unix(UnixHttpListener),
+}
+
+fn serve<L, H>(listener: L,
+               shutdown: Arc<AtomicBool>,
+               keep_alive: Option<Duration>,
+               threads: Option<usize>,
+               handler: H)
+               -> Result<(Listening, Arc<dyn CloseListener>)>
+    where L: 'static + NetworkListener + Send,
+          H: 'static + Handler
+{
+    let listener = ShutdownListener::new(listener, shutdown);
+    let shared = listener.shared.clone();
+    let mut server = Server::new(listener);
+    server.keep_alive(keep_alive);
+    let listening = match threads {
+        Some(threads) => server.handle_threads(handler, threads),
+        None => server.handle(handler),
+    }?;
+    Ok((listening, shared))
+}
+
+pub fn start<T, U>(address: SocketAddr,
+                   handler: T,
+                   extension_routes: &[(Method, &str, U)])
+                   -> Result<ServerHandle<U>>
+    where T: 'static + WebDriverHandler<U>,
+          U: 'static + WebDriverExtensionRoute
+{
+    ServerBuilder::new(address)
+        .extension_routes(extension_routes)
+        .start(handler)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::thread;
+    use std::time::Duration;
+
+    use hyper::Client;
+    use hyper::header::{Allow, Authorization, Basic, Bearer, Host, Origin};
+    use hyper::method::Method;
+    use hyper::status::StatusCode;
+    use rustc_serialize::json::{Json, ToJson};
+
+    use command::{WebDriverMessage, WebDriverCommand};
+    use error::WebDriverResult;
+    use httpapi::VoidWebDriverExtensionRoute;
+    use response::{NewSessionResponse, WebDriverResponse};
+    use command::{ActionsParameters, GetParameters, TimeoutsParameters, VoidWebDriverExtensionCommand};
+    use error::{ErrorStatus, WebDriverError};
+    use response::ValueResponse;
+    use super::{address_unavailable, bind_addresses, start, Authentication, CommandContext,
+                CommandMiddleware, ListenAddress, LoggedWriter, ServerBuilder, ServerHandle,
+                ServerState, Session, SessionTimeouts, WebDriverHandler, BIND_ATTEMPTS};
+
+    /// What `TestHandler` did, in the order it happened.
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        SourceStarted,
+        SessionDeleted,
+    }
+
+    /// Stub handler. `GetPageSource` blocks until the test releases it, so
+    /// that tests control how long the handler stays busy.
+    struct TestHandler {
+        deleted: usize,
+        events: Sender<Event>,
+        release: Receiver<()>,
+    }
+
+    impl WebDriverHandler for TestHandler {
+        fn handle_command(&mut self, _: &Option<Session>, msg: WebDriverMessage)
+                          -> WebDriverResult<WebDriverResponse> {
+            match msg.command {
+                WebDriverCommand::NewSession(_) => Ok(WebDriverResponse::NewSession(
+                    NewSessionResponse::new("test".into(), Json::Null))),
+                WebDriverCommand::DeleteSession => Ok(WebDriverResponse::DeleteSession),
+                WebDriverCommand::GetTitle => panic!("title exploded"),
+                WebDriverCommand::Get(params) => {
+                    Ok(WebDriverResponse::Generic(ValueResponse::new(params.url.to_json())))
+                },
+                WebDriverCommand::GetPageSource => {
+                    let _ = self.events.send(Event::SourceStarted);
+                    // Returns straight away once the probe is dropped
+                    let _ = self.release.recv();
+                    Ok(WebDriverResponse::Void)
+                },
+                _ => Ok(WebDriverResponse::Void),
+            }
+        }
+
+        fn delete_session(&mut self, _: &Option<Session>) {
+            self.deleted += 1;
+            let _ = self.events.send(Event::SessionDeleted);
+        }
+
+        fn status_message(&self) -> Option<String> {
+            Some(format!("deleted {}", self.deleted))
+        }
+    }
+
+    /// The test's end of a `TestHandler`.
+    struct Probe {
+        events: Receiver<Event>,
+        release: Sender<()>,
+    }
+
+    impl Probe {
+        fn next_event(&self) -> Event {
+            self.events.recv_timeout(Duration::from_secs(5)).unwrap()
+        }
+
+        /// Let a blocked `GetPageSource` return.
+        fn release(&self) {
+            self.release.send(()).unwrap();
+        }
+    }
+
+    fn localhost() -> SocketAddr {
+        "127.0.0.1:0".parse().unwrap()
+    }
+
+    fn builder() -> ServerBuilder<VoidWebDriverExtensionRoute> {
+        ServerBuilder::new(localhost())
+    }
+
+    fn probed_handler() -> (TestHandler, Probe) {
+        let (events_send, events_recv) = channel();
+        let (release_send, release_recv) = channel();
+        let handler = TestHandler { deleted: 0, events: events_send, release: release_recv };
+        (handler, Probe { events: events_recv, release: release_send })
+    }
+
+    fn handler() -> TestHandler {
+        probed_handler().0
+    }
+
+    /// Start `builder` with a `TestHandler`.
+    fn serve(builder: ServerBuilder<VoidWebDriverExtensionRoute>)
+             -> (ServerHandle<VoidWebDriverExtensionRoute>, Probe) {
+        let (handler, probe) = probed_handler();
+        (builder.start(handler).unwrap(), probe)
+    }
+
+    fn url_for(server: &ServerHandle<VoidWebDriverExtensionRoute>, path: &str) -> String {
+        format!("http://{}{}", server.local_addr().unwrap(), path)
+    }
+
+    fn new_session(server: &ServerHandle<VoidWebDriverExtensionRoute>, path: &str) -> StatusCode {
+        let mut res = Client::new()
+            .post(&url_for(server, path))
+            .body("{\"capabilities\": {}}")
+            .send()
+            .unwrap();
+        let mut body = String::new();
+        res.read_to_string(&mut body).unwrap();
+        res.status
+    }
+
+    #[test]
+    fn test_shutdown_deletes_session() {
+        let (handler, probe) = probed_handler();
+        let server = start::<_, VoidWebDriverExtensionRoute>(localhost(), handler, &[]).unwrap();
+
+        assert_eq!(new_session(&server, "/session"), StatusCode::Ok);
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+        assert_eq!(probe.next_event(), Event::SessionDeleted);
+    }
+
+    #[test]
+    fn test_shutdown_without_session() {
+        let (handler, probe) = probed_handler();
+        let server = start::<_, VoidWebDriverExtensionRoute>(localhost(), handler, &[]).unwrap();
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+        // The handler is gone without deleting anything
+        assert!(probe.events.recv().is_err());
+    }
+
+    #[test]
+    fn test_shutdown_waits_for_requests() {
+        let (server, probe) = serve(builder());
+        assert_eq!(new_session(&server, "/session"), StatusCode::Ok);
+
+        let source = url_for(&server, "/session/test/source");
+        let request = thread::spawn(move || Client::new().get(&source).send().unwrap().status);
+        assert_eq!(probe.next_event(), Event::SourceStarted);
+
+        // Only let the handler return once shutdown has started
+        let shutting_down = server.shutdown.clone();
+        let release = probe.release.clone();
+        thread::spawn(move || {
+            while !shutting_down.load(Ordering::SeqCst) {
+                thread::yield_now();
+            }
+            release.send(()).unwrap();
+        });
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+        assert_eq!(request.join().unwrap(), StatusCode::Ok);
+        assert_eq!(probe.next_event(), Event::SessionDeleted);
+    }
+
+    #[test]
+    fn test_shutdown_closes_socket() {
+        use std::net::TcpStream;
+
+        let server = builder().threads(3).start(handler()).unwrap();
+        let addr = server.local_addr().unwrap();
+        assert_eq!(new_session(&server, "/session"), StatusCode::Ok);
+        server.shutdown(Duration::from_secs(5)).unwrap();
+
+        assert!(TcpStream::connect(addr).is_err());
+        let server = ServerBuilder::<VoidWebDriverExtensionRoute>::new(addr)
+            .start(handler())
+            .unwrap();
+        assert_eq!(new_session(&server, "/session"), StatusCode::Ok);
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_refuse_new_sessions() {
+        let server = builder().refuse_new_sessions(true).start(handler()).unwrap();
+        assert_eq!(new_session(&server, "/session"), StatusCode::InternalServerError);
+        assert_eq!(server.state.status().find("ready"), Some(&Json::Boolean(false)));
+        server.shutdown(Duration::from_secs(5)).unwrap();
+
+        let server = builder().refuse_new_sessions(false).start(handler()).unwrap();
+        assert_eq!(new_session(&server, "/session"), StatusCode::Ok);
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_allowed_hosts() {
+        let server = builder()
+            .allowed_hosts(vec!["example.test".into()])
+            .start(handler())
+            .unwrap();
+        let url = url_for(&server, "/status");
+        let status = |hostname: &str| {
+            Client::new()
+                .get(&url)
+                .header(Host { hostname: hostname.into(), port: Some(4444) })
+                .send()
+                .unwrap()
+                .status
+        };
+
+        assert_eq!(status("127.0.0.1"), StatusCode::Ok);
+        assert_eq!(status("[::1]"), StatusCode::Ok);
+        assert_eq!(status("example.test"), StatusCode::Ok);
+        assert_eq!(status("localhost"), StatusCode::BadRequest);
+        assert_eq!(status("192.168.0.1"), StatusCode::BadRequest);
+        assert_eq!(status("rebind.example.com"), StatusCode::BadRequest);
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_allowed_origins() {
+        let server = builder()
+            .allowed_origins(vec!["http://localhost:8000".into()])
+            .start(handler())
+            .unwrap();
+        let url = url_for(&server, "/session");
+        let status = |origin: &str| {
+            let mut res = Client::new()
+                .post(&url)
+                .header(Origin::new("http", origin, Some(8000)))
+                .body("{\"capabilities\": {}}")
+                .send()
+                .unwrap();
+            let mut body = String::new();
+            res.read_to_string(&mut body).unwrap();
+            (res.status, body)
+        };
+
+        let (status_code, body) = status("evil.example.com");
+        assert_eq!(status_code, StatusCode::BadRequest);
+        assert!(body.contains("Invalid Origin header http://evil.example.com:8000"));
+        assert_eq!(status("localhost").0, StatusCode::Ok);
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_url_prefix() {
+        let server = builder().url_prefix("/wd/hub/").start(handler()).unwrap();
+
+        assert_eq!(new_session(&server, "/session"), StatusCode::NotFound);
+        assert_eq!(new_session(&server, "/wd/hubsession"), StatusCode::NotFound);
+        assert_eq!(new_session(&server, "/wd/hub/session"), StatusCode::Ok);
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_handler_panic() {
+        let (server, probe) = serve(builder());
+
+        assert_eq!(new_session(&server, "/session"), StatusCode::Ok);
+        let mut res = Client::new().get(&url_for(&server, "/session/test/title")).send().unwrap();
+        let mut body = String::new();
+        res.read_to_string(&mut body).unwrap();
+        assert_eq!(res.status, StatusCode::InternalServerError);
+        let value = Json::from_str(&body).unwrap();
+        assert_eq!(value.find_path(&["value", "error"]).unwrap().as_string(),
+                   Some("unknown error"));
+        assert!(value.find_path(&["value", "message"]).unwrap().as_string().unwrap()
+                .contains("title exploded"));
+        assert_eq!(probe.next_event(), Event::SessionDeleted);
+
+        // The dispatcher is still around to serve a new session
+        assert_eq!(new_session(&server, "/session"), StatusCode::Ok);
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_command_deadline() {
+        let (server, probe) = serve(builder().command_deadline(|cmd| match *cmd {
+            WebDriverCommand::GetPageSource => Some(Duration::from_millis(100)),
+            _ => None
+        }));
+
+        assert_eq!(new_session(&server, "/session"), StatusCode::Ok);
+        let res = Client::new().get(&url_for(&server, "/session/test/source")).send().unwrap();
+        assert_eq!(res.status, StatusCode::RequestTimeout);
+        assert_eq!(probe.next_event(), Event::SourceStarted);
+
+        // Fails rather than queueing while the handler is still stuck
+        let res = Client::new().get(&url_for(&server, "/session/test/url")).send().unwrap();
+        assert_eq!(res.status, StatusCode::InternalServerError);
+
+        probe.release();
+        assert_eq!(probe.next_event(), Event::SessionDeleted);
+        while server.state.unhealthy() {
+            thread::yield_now();
+        }
+        assert_eq!(new_session(&server, "/session"), StatusCode::Ok);
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_queued_command_deadline() {
+        // Needs a spare HTTP thread for the queued command
+        let (server, probe) = serve(builder().threads(4).command_deadline(|cmd| match *cmd {
+            WebDriverCommand::GetCurrentUrl => Some(Duration::from_millis(100)),
+            _ => None
+        }));
+
+        assert_eq!(new_session(&server, "/session"), StatusCode::Ok);
+        let source = url_for(&server, "/session/test/source");
+        let busy = thread::spawn(move || Client::new().get(&source).send().unwrap().status);
+        assert_eq!(probe.next_event(), Event::SourceStarted);
+
+        let current_url = url_for(&server, "/session/test/url");
+        let queued = thread::spawn(move || Client::new().get(&current_url).send().unwrap().status);
+        while server.state.progress.lock().unwrap().sent < 3 {
+            thread::yield_now();
+        }
+        // Longer than the deadline, which only starts once the dispatcher
+        // gets to the command
+        thread::sleep(Duration::from_millis(200));
+
+        probe.release();
+        assert_eq!(busy.join().unwrap(), StatusCode::Ok);
+        assert_eq!(queued.join().unwrap(), StatusCode::Ok);
+        assert!(!server.state.unhealthy());
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_session_timeouts() {
+        let mut timeouts = SessionTimeouts::from_capabilities(&Json::from_str(
+            r#"{"timeouts": {"script": null, "pageLoad": 1000}}"#).unwrap());
+        assert_eq!(timeouts, SessionTimeouts { script: None, page_load: 1000, implicit: 0 });
+
+        timeouts.update(&TimeoutsParameters { script: Some(10), page_load: None, implicit: Some(5) });
+        assert_eq!(timeouts, SessionTimeouts { script: Some(10), page_load: 1000, implicit: 5 });
+
+        let duration = |cmd: WebDriverCommand<VoidWebDriverExtensionCommand>| timeouts.allowed_duration(&cmd);
+        assert_eq!(duration(WebDriverCommand::GetTitle), None);
+        assert_eq!(duration(WebDriverCommand::Refresh), Some(1000));
+        assert_eq!(duration(WebDriverCommand::ReleaseActions), None);
+        assert_eq!(duration(WebDriverCommand::PerformActions(ActionsParameters { actions: vec![] })), None);
+    }
+
+    #[test]
+    fn test_stuck_commands() {
+        let state = ServerState::new();
+        let first = state.next_command();
+        let second = state.next_command();
+
+        // A command that completes before the deadline is noticed isn't stuck
+        assert!(!state.complete(first));
+        assert!(!state.mark_stuck(first));
+        assert!(!state.unhealthy());
+
+        assert!(state.mark_stuck(second));
+        assert!(state.unhealthy());
+        assert!(state.complete(second));
+        assert!(state.unhealthy());
+        state.recovered(second);
+        assert!(!state.unhealthy());
+    }
+
+    #[test]
+    fn test_status_while_busy() {
+        // Needs a spare HTTP thread for the status request
+        let (server, probe) = serve(builder().threads(4));
+        let status_url = url_for(&server, "/status");
+        let status = || {
+            let mut res = Client::new().get(&status_url).send().unwrap();
+            let mut body = String::new();
+            res.read_to_string(&mut body).unwrap();
+            Json::from_str(&body).unwrap()
+        };
+
+        let value = status();
+        assert_eq!(value.find_path(&["value", "ready"]), Some(&Json::Boolean(true)));
+        assert_eq!(value.find_path(&["value", "sessions"]), Some(&Json::U64(0)));
+        assert_eq!(value.find_path(&["value", "message"]), Some(&"deleted 0".to_json()));
+
+        assert_eq!(new_session(&server, "/session"), StatusCode::Ok);
+        let source = url_for(&server, "/session/test/source");
+        let busy = thread::spawn(move || Client::new().get(&source).send().unwrap().status);
+        assert_eq!(probe.next_event(), Event::SourceStarted);
+
+        // Answered while the handler is blocked
+        let value = status();
+        assert_eq!(value.find_path(&["value", "ready"]), Some(&Json::Boolean(false)));
+        assert_eq!(value.find_path(&["value", "sessions"]), Some(&Json::U64(1)));
+
+        probe.release();
+        assert_eq!(busy.join().unwrap(), StatusCode::Ok);
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    struct RewriteMiddleware;
+
+    impl CommandMiddleware for RewriteMiddleware {
+        fn before(&mut self, msg: &mut WebDriverMessage, _: &CommandContext) -> WebDriverResult<()> {
+            if let WebDriverCommand::Get(ref mut params) = msg.command {
+                if params.url == "forbidden" {
+                    return Err(WebDriverError::new(ErrorStatus::InvalidArgument, "forbidden"));
+                }
+                *params = GetParameters { url: format!("{}/rewritten", params.url) };
+            }
+            Ok(())
+        }
+    }
+
+    struct RecordMiddleware(Arc<AtomicUsize>);
+
+    impl CommandMiddleware for RecordMiddleware {
+        fn after(&mut self,
+                 msg: &WebDriverMessage,
+                 result: &mut WebDriverResult<WebDriverResponse>,
+                 context: &CommandContext) {
+            assert_eq!(context.session_id, msg.session_id);
+            if let (&WebDriverCommand::Get(ref params), true) = (&msg.command, result.is_ok()) {
+                // Sees the command as rewritten by later middleware
+                assert!(params.url.ends_with("/rewritten"));
+            }
+            if result.is_err() {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    struct PanicMiddleware;
+
+    impl CommandMiddleware for PanicMiddleware {
+        fn before(&mut self, msg: &mut WebDriverMessage, _: &CommandContext) -> WebDriverResult<()> {
+            if let WebDriverCommand::Get(ref params) = msg.command {
+                if params.url == "panic" {
+                    panic!("middleware exploded");
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_middleware() {
+        let errors = Arc::new(AtomicUsize::new(0));
+        let server = builder()
+            .middleware(RecordMiddleware(errors.clone()))
+            .middleware(PanicMiddleware)
+            .middleware(RewriteMiddleware)
+            .start(handler())
+            .unwrap();
+        assert_eq!(new_session(&server, "/session"), StatusCode::Ok);
+
+        let url = url_for(&server, "/session/test/url");
+        let mut res = Client::new().post(&url).body(r#"{"url": "a"}"#).send().unwrap();
+        let mut body = String::new();
+        res.read_to_string(&mut body).unwrap();
+        assert_eq!(Json::from_str(&body).unwrap().find("value"), Some(&"a/rewritten".to_json()));
+        assert_eq!(errors.load(Ordering::SeqCst), 0);
+
+        let res = Client::new().post(&url).body(r#"{"url": "forbidden"}"#).send().unwrap();
+        assert_eq!(res.status, StatusCode::BadRequest);
+        assert_eq!(errors.load(Ordering::SeqCst), 1);
+
+        let res = Client::new().post(&url).body(r#"{"url": "panic"}"#).send().unwrap();
+        assert_eq!(res.status, StatusCode::InternalServerError);
+        assert_eq!(errors.load(Ordering::SeqCst), 2);
+
+        // The dispatcher survives and the session is kept
+        let res = Client::new().post(&url).body(r#"{"url": "b"}"#).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_metrics() {
+        let server = builder().metrics_path("/metrics").start(handler()).unwrap();
+        assert_eq!(new_session(&server, "/session"), StatusCode::Ok);
+        let url = url_for(&server, "/session/test/unknown");
+        assert_eq!(Client::new().post(&url).send().unwrap().status, StatusCode::NotFound);
+
+        let url = url_for(&server, "/metrics");
+        let mut res = Client::new().get(&url).send().unwrap();
+        let mut body = String::new();
+        res.read_to_string(&mut body).unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        let lines = body.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&r#"webdriver_requests_total{method="POST",route="/session"} 1"#));
+        assert!(lines.contains(&r#"webdriver_requests_total{method="POST",route="unmatched"} 1"#));
+        assert!(lines.contains(&r#"webdriver_errors_total{error="unknown command"} 1"#));
+        assert!(lines.contains(&"webdriver_active_sessions 1"));
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_method_not_allowed() {
+        let server = builder().start(handler()).unwrap();
+        let url = url_for(&server, "/session/test/cookie/a");
+
+        let mut res = Client::new().post(&url).body("{}").send().unwrap();
+        let mut body = String::new();
+        res.read_to_string(&mut body).unwrap();
+        assert_eq!(res.status, StatusCode::MethodNotAllowed);
+        assert_eq!(res.headers.get::<Allow>(), Some(&Allow(vec![Method::Get, Method::Delete])));
+        let value = Json::from_str(&body).unwrap();
+        assert_eq!(value.find_path(&["value", "error"]).unwrap().as_string(),
+                   Some("unknown method"));
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    /// Send `request` followed by `body` over a new connection and return
+    /// the status code and the error code of the response.
+    fn send_raw(server: &ServerHandle<VoidWebDriverExtensionRoute>, request: &str, body: &[u8])
+                -> (String, Option<String>) {
+        use std::io::Write;
+        use std::net::TcpStream;
+
+        let mut stream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        // The server may respond and close before the body is sent.
+        let _ = stream.write_all(body);
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response.split(' ').nth(1).unwrap().to_string();
+        let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+        let error = Json::from_str(body).ok()
+            .and_then(|x| x.find_path(&["value", "error"]).and_then(|x| x.as_string())
+                      .map(|x| x.to_string()));
+        (status, error)
+    }
+
+    fn post_raw(server: &ServerHandle<VoidWebDriverExtensionRoute>,
+                content_type: Option<&str>,
+                body: &[u8])
+                -> (String, Option<String>) {
+        let content_type = content_type
+            .map(|x| format!("Content-Type: {}\r\n", x))
+            .unwrap_or_default();
+        let request = format!("POST /session/test/url HTTP/1.1\r\nHost: localhost\r\n{}\
+                               Content-Length: {}\r\nConnection: close\r\n\r\n",
+                              content_type, body.len());
+        send_raw(server, &request, body)
+    }
+
+    fn ok() -> (String, Option<String>) {
+        ("200".into(), None)
+    }
+
+    fn invalid(status: &str) -> (String, Option<String>) {
+        (status.into(), Some("invalid argument".into()))
+    }
+
+    #[test]
+    fn test_body_too_large() {
+        let server = builder().max_body_size(32).start(handler()).unwrap();
+        let body = br#"{"url": "http://example.test/"}"#;
+        assert_eq!(body.len(), 31);
+
+        assert_eq!(post_raw(&server, None, body), ok());
+        let body = br#"{"url": "http://example.test/a/"}"#;
+        assert_eq!(post_raw(&server, None, body), invalid("413"));
+
+        // Without a Content-Length the limit is enforced while reading.
+        let request = "POST /session/test/url HTTP/1.1\r\nHost: localhost\r\n\
+                       Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n";
+        let chunked = format!("{:x}\r\n{}\r\n0\r\n\r\n",
+                              body.len(), String::from_utf8_lossy(body));
+        assert_eq!(send_raw(&server, request, chunked.as_bytes()), invalid("413"));
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+
+        let server = builder().max_body_size(None).start(handler()).unwrap();
+        assert_eq!(post_raw(&server, None, body), ok());
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_utf8_body() {
+        let server = builder().start(handler()).unwrap();
+
+        assert_eq!(post_raw(&server, None, b"{\"url\": \"\xff\xfe\"}"), invalid("400"));
+        assert_eq!(post_raw(&server, None, "{\"url\": \"\u{e9}\"}".as_bytes()), ok());
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_truncated_body() {
+        use std::io::Write;
+        use std::net::{Shutdown, TcpStream};
+
+        let server = builder().start(handler()).unwrap();
+        let mut stream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        stream.write_all(b"POST /session/test/url HTTP/1.1\r\nHost: localhost\r\n\
+                           Content-Length: 100\r\n\r\n{\"url\": ").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.contains("\"error\":\"invalid argument\""));
+
+        // The worker survives to handle further requests.
+        assert_eq!(post_raw(&server, None, br#"{"url": "http://example.test/"}"#), ok());
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_unsupported_content_type() {
+        let server = builder().start(handler()).unwrap();
+        let body = br#"{"url": "http://example.test/"}"#;
+
+        assert_eq!(post_raw(&server, Some("application/json"), body), ok());
+        assert_eq!(post_raw(&server, Some("application/json; charset=UTF-8"), body), ok());
+        assert_eq!(post_raw(&server, None, body), ok());
+        // Types a page can send cross-origin without a preflight
+        assert_eq!(post_raw(&server, Some("text/plain"), body), invalid("415"));
+        assert_eq!(post_raw(&server, Some("application/x-www-form-urlencoded"), body),
+                   invalid("415"));
+        assert_eq!(post_raw(&server, Some("multipart/form-data; boundary=x"), body),
+                   invalid("415"));
+        assert_eq!(post_raw(&server, Some("application/octet-stream"), body), invalid("415"));
+        assert_eq!(post_raw(&server, Some("application/json; charset=latin1"), body),
+                   invalid("415"));
+        assert_eq!(post_raw(&server, Some("not a type"), body), invalid("415"));
+        assert_eq!(post_raw(&server, Some("text/plain"), b""), invalid("415"));
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_logged_writer() {
+        use std::io::Write;
+
+        let mut writer = LoggedWriter::new(vec![], 4);
+        writer.write_all(b"abc").unwrap();
+        writer.write_all(b"defg").unwrap();
+        assert_eq!(writer.inner, b"abcdefg");
+        assert_eq!(writer.logged, b"abcd");
+        assert_eq!(writer.written, 7);
+
+        let mut writer = LoggedWriter::new(vec![], 0);
+        writer.write_all(b"abc").unwrap();
+        assert!(writer.logged.is_empty());
+    }
+
+    #[test]
+    fn test_gzip_responses() {
+        use flate2::read::GzDecoder;
+        use hyper::header::{qitem, AcceptEncoding, ContentEncoding, Encoding, Quality, QualityItem};
+
+        let server = builder().compression_threshold(1024).start(handler()).unwrap();
+        let url = url_for(&server, "/session/test/url");
+        let page = format!("http://example.test/{}", "a".repeat(2000));
+        let send = |page: &str, encodings: Vec<QualityItem<Encoding>>| {
+            let body = format!("{{\"url\": \"{}\"}}", page);
+            let client = Client::new();
+            let mut request = client.post(&url).body(&body[..]);
+            if !encodings.is_empty() {
+                request = request.header(AcceptEncoding(encodings));
+            }
+            let mut res = request.send().unwrap();
+            assert_eq!(res.headers.get_raw("Vary"), Some(&[b"Accept-Encoding".to_vec()][..]));
+            let mut body = vec![];
+            res.read_to_end(&mut body).unwrap();
+            let gzipped = match res.headers.get::<ContentEncoding>() {
+                Some(encoding) => {
+                    assert_eq!(encoding, &ContentEncoding(vec![Encoding::Gzip]));
+                    let mut decoded = vec![];
+                    GzDecoder::new(&body[..]).read_to_end(&mut decoded).unwrap();
+                    body = decoded;
+                    true
+                },
+                None => false
+            };
+            let value = Json::from_str(&String::from_utf8(body).unwrap()).unwrap();
+            assert_eq!(value.find("value").unwrap().as_string(), Some(page));
+            gzipped
+        };
+
+        assert!(send(&page, vec![qitem(Encoding::Gzip)]));
+        assert!(send(&page, vec![qitem(Encoding::Deflate), qitem(Encoding::EncodingExt("*".into()))]));
+        assert!(!send(&page, vec![]));
+        assert!(!send(&page, vec![qitem(Encoding::Identity)]));
+        assert!(!send(&page, vec![QualityItem::new(Encoding::Gzip, Quality(0)),
+                                  qitem(Encoding::EncodingExt("*".into()))]));
+        // Small responses aren't worth compressing.
+        assert!(!send("http://example.test/", vec![qitem(Encoding::Gzip)]));
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+
+        let server = builder().compression_threshold(None).start(handler()).unwrap();
+        let url = url_for(&server, "/session/test/url");
+        let body = format!("{{\"url\": \"{}\"}}", page);
+        let res = Client::new()
+            .post(&url)
+            .body(&body[..])
+            .header(AcceptEncoding(vec![qitem(Encoding::Gzip)]))
+            .send()
+            .unwrap();
+        assert!(res.headers.get::<ContentEncoding>().is_none());
+        assert!(res.headers.get_raw("Vary").is_none());
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_streamed_response() {
+        use hyper::header::{ContentLength, TransferEncoding};
+
+        let server = builder().compression_threshold(None).start(handler()).unwrap();
+        let url = url_for(&server, "/session/test/url");
+        let send = |page: &str| {
+            let body = format!("{{\"url\": \"{}\"}}", page);
+            let mut res = Client::new().post(&url).body(&body[..]).send().unwrap();
+            let mut body = String::new();
+            res.read_to_string(&mut body).unwrap();
+            let value = Json::from_str(&body).unwrap();
+            assert_eq!(value.find("value").unwrap().as_string(), Some(page));
+            (res.headers.get::<ContentLength>().map(|x| x.0 as usize),
+             res.headers.has::<TransferEncoding>())
+        };
+
+        let page = "http://example.test/";
+        assert_eq!(send(page), (Some(page.len() + 12), false));
+        let page = format!("http://example.test/{}", "a".repeat(100 * 1024));
+        assert_eq!(send(&page), (None, true));
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_bearer_authentication() {
+        let server = builder()
+            .authentication(Authentication::Bearer("secret".into()))
+            .max_body_size(16)
+            .start(handler())
+            .unwrap();
+        let url = url_for(&server, "/status");
+
+        let mut res = Client::new().get(&url).send().unwrap();
+        let mut body = String::new();
+        res.read_to_string(&mut body).unwrap();
+        assert_eq!(res.status, StatusCode::Unauthorized);
+        assert!(res.headers.get_raw("WWW-Authenticate").is_some());
+        let value = Json::from_str(&body).unwrap();
+        assert_eq!(value.find_path(&["value", "error"]), Some(&"unauthorized".to_json()));
+        assert_eq!(value.find_path(&["value", "message"]),
+                   Some(&"Missing or invalid credentials".to_json()));
+
+        let res = Client::new()
+            .get(&url)
+            .header(Authorization(Bearer { token: "wrong".into() }))
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Unauthorized);
+
+        // Credentials are checked before the body is looked at
+        let session = url_for(&server, "/session");
+        let res = Client::new().post(&session).body(&"x".repeat(64)[..]).send().unwrap();
+        assert_eq!(res.status, StatusCode::Unauthorized);
+
+        let res = Client::new()
+            .get(&url)
+            .header(Authorization(Bearer { token: "secret".into() }))
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_basic_authentication() {
+        let auth = Authentication::Basic { username: "user".into(), password: "pass".into() };
+        let server = builder().authentication(auth).start(handler()).unwrap();
+        let url = url_for(&server, "/status");
+        let status = |username: &str, password: &str| {
+            Client::new()
+                .get(&url)
+                .header(Authorization(Basic {
+                    username: username.into(),
+                    password: Some(password.into()),
+                }))
+                .send()
+                .unwrap()
+                .status
+        };
+
+        assert_eq!(status("user", "wrong"), StatusCode::Unauthorized);
+        assert_eq!(status("other", "pass"), StatusCode::Unauthorized);
+        assert_eq!(status("user", "pass"), StatusCode::Ok);
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_localhost_addresses() {
+        use std::io::Write;
+        use std::net::TcpStream;
+
+        let server = ServerBuilder::<VoidWebDriverExtensionRoute>::localhost(0)
+            .start(handler())
+            .unwrap();
+        let addrs = server.addresses()
+            .iter()
+            .map(|address| match *address {
+                ListenAddress::Tcp(addr) => addr,
+                ref address => panic!("Unexpected address {:?}", address),
+            })
+            .collect::<Vec<_>>();
+        // The IPv6 address is missing on hosts without IPv6
+        assert!(addrs.len() == 1 || addrs.len() == 2);
+        assert!(addrs[0].is_ipv4() && addrs[1..].iter().all(|x| x.is_ipv6()));
+        assert!(addrs[0].port() != 0);
+        assert!(addrs.iter().all(|x| x.port() == addrs[0].port()));
+        assert_eq!(server.local_addr(), Some(addrs[0]));
+
+        // hyper's client can't resolve bracketed IPv6 hosts, so talk HTTP
+        // directly.
+        for addr in addrs.iter() {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"GET /status HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        }
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_shared_port_in_use() {
+        use std::io;
+        use std::net::TcpListener;
+
+        if TcpListener::bind("[::1]:0").is_err() {
+            // No IPv6 on this host
+            return;
+        }
+        let addresses = [ListenAddress::Tcp(localhost()),
+                         ListenAddress::Tcp("[::1]:0".parse().unwrap())];
+        // Binds `address`, first taking the port for IPv6 if `take` says so
+        let bind = |address: &ListenAddress, taken: &mut Vec<TcpListener>, take: bool| {
+            let addr = match *address {
+                ListenAddress::Tcp(addr) => addr,
+                ref address => panic!("Unexpected address {:?}", address),
+            };
+            let listener = TcpListener::bind(addr)?;
+            let local_addr = listener.local_addr()?;
+            if local_addr.is_ipv4() && take {
+                taken.push(TcpListener::bind(("::1", local_addr.port()))?);
+            }
+            Ok((ListenAddress::Tcp(local_addr), listener))
+        };
+
+        let mut taken = vec![];
+        let bound = bind_addresses(&addresses, &[], |address| {
+            let take = taken.is_empty();
+            bind(address, &mut taken, take)
+        }).unwrap();
+        let ports = bound.iter()
+            .map(|&(ref address, _)| match *address {
+                ListenAddress::Tcp(addr) => addr.port(),
+                ref address => panic!("Unexpected address {:?}", address),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ports.len(), 2);
+        assert_eq!(ports[0], ports[1]);
+        assert!(ports[0] != taken[0].local_addr().unwrap().port());
+
+        // Gives up if the picked port keeps being taken
+        let mut taken = vec![];
+        let err = bind_addresses(&addresses, &[], |address| bind(address, &mut taken, true))
+            .err()
+            .unwrap();
+        match err {
+            ::hyper::Error::Io(ref e) => assert_eq!(e.kind(), io::ErrorKind::AddrInUse),
+            ref e => panic!("Unexpected error {:?}", e),
+        }
+        assert_eq!(taken.len(), BIND_ATTEMPTS);
+    }
+
+    #[test]
+    fn test_address_unavailable() {
+        use std::io;
+
+        assert!(address_unavailable(&io::Error::new(io::ErrorKind::AddrNotAvailable, "")));
+        assert!(!address_unavailable(&io::Error::new(io::ErrorKind::AddrInUse, "")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket() {
+        use std::env;
+        use std::fs;
+        use std::io::Write;
+        use std::os::unix::net::UnixStream;
+        use std::process;
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        // A fresh directory, so neither a stale socket nor a concurrent test
+        // run can get in the way
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+        let dir = env::temp_dir().join(format!("webdriver-test-{}-{}", process::id(), nanos));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("webdriver.sock");
+        let _ = fs::remove_file(&path);
+        let server = ServerBuilder::<VoidWebDriverExtensionRoute>::unix(&path)
+            .start(handler())
+            .unwrap();
+        assert_eq!(server.address(), &ListenAddress::Unix(path.clone()));
+        assert_eq!(server.local_addr(), None);
+
+        let request = |method: &str, url: &str, body: &str| {
+            let mut stream = UnixStream::connect(&path).unwrap();
+            write!(stream,
+                   "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\
+                    Connection: close\r\n\r\n{}",
+                   method, url, body.len(), body).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = request("POST", "/session", "{\"capabilities\": {}}");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\"sessionId\":\"test\""));
+
+        let response = request("POST", "/session/test/url", "{\"url\": \"http://example.com/\"}");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("{\"value\":\"http://example.com/\"}"));
+
+        let response = request("GET", "/session/test/url", "");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+        assert!(!path.exists());
+        fs::remove_dir(&dir).unwrap();
+    }
 }
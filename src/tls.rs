@@ -0,0 +1,309 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use hyper::{Error, Result};
+use hyper::net::{HttpStream, NetworkStream, SslServer};
+use openssl::error::ErrorStack;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslStream, SslVerifyMode};
+use openssl::x509::X509Name;
+
+fn ssl_error(err: ErrorStack) -> Error {
+    Error::Ssl(Box::new(err))
+}
+
+/// TLS configuration for serving HTTPS, backed by OpenSSL.
+#[derive(Clone)]
+pub struct TlsServer {
+    acceptor: Arc<SslAcceptor>,
+}
+
+impl TlsServer {
+    /// Load the PEM encoded certificate chain and private key.
+    ///
+    /// If `client_ca` is given, clients must present a certificate signed by
+    /// one of the PEM encoded certificates it contains.
+    pub fn new(certificate: &Path, private_key: &Path, client_ca: Option<&Path>)
+               -> Result<TlsServer> {
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())
+            .map_err(ssl_error)?;
+        builder.set_certificate_chain_file(certificate).map_err(ssl_error)?;
+        builder.set_private_key_file(private_key, SslFiletype::PEM).map_err(ssl_error)?;
+        builder.check_private_key().map_err(ssl_error)?;
+        if let Some(client_ca) = client_ca {
+            builder.set_ca_file(client_ca).map_err(ssl_error)?;
+            builder.set_client_ca_list(X509Name::load_client_ca_file(client_ca)
+                                       .map_err(ssl_error)?);
+            builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        }
+        Ok(TlsServer {
+            acceptor: Arc::new(builder.build()),
+        })
+    }
+}
+
+impl SslServer<HttpStream> for TlsServer {
+    type Stream = TlsStream;
+
+    fn wrap_server(&self, stream: HttpStream) -> Result<TlsStream> {
+        match self.acceptor.accept(stream) {
+            Ok(stream) => Ok(TlsStream(Arc::new(Mutex::new(stream)))),
+            Err(e) => Err(Error::Ssl(Box::new(e)))
+        }
+    }
+}
+
+/// Connection protected by TLS.
+///
+/// hyper needs to clone streams to read requests and write responses, which
+/// an `SslStream` doesn't support, so access is shared through a mutex.
+#[derive(Clone)]
+pub struct TlsStream(Arc<Mutex<SslStream<HttpStream>>>);
+
+impl TlsStream {
+    fn lock(&self) -> io::Result<MutexGuard<'_, SslStream<HttpStream>>> {
+        self.0.lock().map_err(|_| io::Error::new(io::ErrorKind::Other, "TLS stream poisoned"))
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.lock()?.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock()?.flush()
+    }
+}
+
+impl NetworkStream for TlsStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.lock()?.get_mut().peer_addr()
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.lock()?.get_ref().set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.lock()?.get_ref().set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        let mut stream = self.lock()?;
+        // Best effort, the peer may not wait for the close notification.
+        let _ = stream.shutdown();
+        stream.get_mut().close(how)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::{self, Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use std::time::Duration;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::{BigNum, MsbOption};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::ssl::{SslConnector, SslFiletype, SslMethod};
+    use openssl::x509::{X509, X509NameBuilder};
+    use openssl::x509::extension::{BasicConstraints, KeyUsage, SubjectAlternativeName};
+
+    use command::WebDriverMessage;
+    use error::WebDriverResult;
+    use httpapi::VoidWebDriverExtensionRoute;
+    use response::WebDriverResponse;
+    use server::{ServerBuilder, ServerHandle, Session, WebDriverHandler};
+
+    struct StubHandler;
+
+    impl WebDriverHandler for StubHandler {
+        fn handle_command(&mut self, _: &Option<Session>, _: WebDriverMessage)
+                          -> WebDriverResult<WebDriverResponse> {
+            Ok(WebDriverResponse::Void)
+        }
+
+        fn delete_session(&mut self, _: &Option<Session>) {}
+    }
+
+    fn private_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    /// Create a certificate for `key`, signed by `issuer` or self-signed as a
+    /// CA certificate.
+    fn certificate(common_name: &str,
+                   key: &PKey<Private>,
+                   issuer: Option<(&X509, &PKey<Private>)>)
+                   -> X509 {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", common_name).unwrap();
+        let name = name.build();
+
+        let mut serial = BigNum::new().unwrap();
+        serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(&serial.to_asn1_integer().unwrap()).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(issuer.map(|(ca, _)| ca.subject_name()).unwrap_or(&name)).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        match issuer {
+            None => {
+                builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                    .unwrap();
+                builder.append_extension(KeyUsage::new().key_cert_sign().build().unwrap()).unwrap();
+            },
+            Some((ca, _)) => {
+                let san = SubjectAlternativeName::new()
+                    .dns("localhost")
+                    .ip("127.0.0.1")
+                    .build(&builder.x509v3_context(Some(ca), None))
+                    .unwrap();
+                builder.append_extension(san).unwrap();
+            }
+        }
+        builder.sign(issuer.map(|(_, key)| key).unwrap_or(key), MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    /// Certificates and keys for a test, written out as PEM files.
+    struct Pki {
+        dir: PathBuf,
+    }
+
+    impl Pki {
+        fn new(name: &str) -> Pki {
+            let dir = env::temp_dir().join(format!("webdriver-tls-{}-{}", process::id(), name));
+            fs::create_dir_all(&dir).unwrap();
+
+            let ca_key = private_key();
+            let ca = certificate("Test CA", &ca_key, None);
+            fs::write(dir.join("ca.pem"), ca.to_pem().unwrap()).unwrap();
+            for name in &["server", "client"] {
+                let key = private_key();
+                let cert = certificate(name, &key, Some((&ca, &ca_key)));
+                fs::write(dir.join(format!("{}.pem", name)), cert.to_pem().unwrap()).unwrap();
+                fs::write(dir.join(format!("{}-key.pem", name)),
+                          key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+            }
+            Pki { dir: dir }
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.dir.join(name)
+        }
+    }
+
+    impl Drop for Pki {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn builder(pki: &Pki) -> ServerBuilder<VoidWebDriverExtensionRoute> {
+        ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .tls(pki.path("server.pem"), pki.path("server-key.pem"))
+    }
+
+    fn get_status(addr: SocketAddr, ca: &Path, client: Option<(&Path, &Path)>)
+                  -> io::Result<String> {
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_ca_file(ca).unwrap();
+        if let Some((certificate, private_key)) = client {
+            connector.set_certificate_file(certificate, SslFiletype::PEM).unwrap();
+            connector.set_private_key_file(private_key, SslFiletype::PEM).unwrap();
+        }
+        let stream = TcpStream::connect(addr)?;
+        let mut stream = connector.build()
+            .connect("localhost", stream)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        stream.write_all(b"GET /status HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    fn shutdown(server: ServerHandle<VoidWebDriverExtensionRoute>) {
+        server.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_https() {
+        let pki = Pki::new("https");
+        let server = builder(&pki).start(StubHandler).unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let response = get_status(addr, &pki.path("ca.pem"), None).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\"ready\":true"));
+
+        // Plain HTTP requests don't get a response.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /status HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        assert!(!response.starts_with("HTTP/1.1 200"));
+
+        shutdown(server);
+    }
+
+    #[test]
+    fn test_https_client_certificate() {
+        let pki = Pki::new("client-certificate");
+        let server = builder(&pki)
+            .tls_client_ca(pki.path("ca.pem"))
+            .start(StubHandler)
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let ca = pki.path("ca.pem");
+
+        match get_status(addr, &ca, None) {
+            Ok(response) => assert!(!response.starts_with("HTTP/1.1 200")),
+            Err(_) => {}
+        }
+
+        // A certificate from a different CA is rejected too.
+        let other = Pki::new("client-certificate-other");
+        let client = (other.path("client.pem"), other.path("client-key.pem"));
+        match get_status(addr, &ca, Some((&client.0, &client.1))) {
+            Ok(response) => assert!(!response.starts_with("HTTP/1.1 200")),
+            Err(_) => {}
+        }
+
+        let client = (pki.path("client.pem"), pki.path("client-key.pem"));
+        let response = get_status(addr, &ca, Some((&client.0, &client.1))).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        shutdown(server);
+    }
+
+    #[test]
+    fn test_invalid_certificate() {
+        let pki = Pki::new("invalid");
+        let result = ServerBuilder::<VoidWebDriverExtensionRoute>::new("127.0.0.1:0".parse().unwrap())
+            .tls(pki.path("server.pem"), pki.path("client-key.pem"))
+            .start(StubHandler);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::Result;
+use hyper::net::{NetworkListener, NetworkStream};
+
+/// Address reported for peers and listeners that don't have an IP address.
+///
+/// hyper drops connections whose peer address can't be determined, so this
+/// is used in place of an error.
+fn unspecified_addr() -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0)
+}
+
+/// Listener accepting HTTP connections on a Unix domain socket.
+#[derive(Clone)]
+pub struct UnixHttpListener {
+    listener: Arc<UnixListener>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl UnixHttpListener {
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixHttpListener> {
+        Ok(UnixHttpListener {
+            listener: Arc::new(UnixListener::bind(path)?),
+            read_timeout: None,
+            write_timeout: None,
+        })
+    }
+}
+
+impl NetworkListener for UnixHttpListener {
+    type Stream = UnixHttpStream;
+
+    fn accept(&mut self) -> Result<UnixHttpStream> {
+        let (stream, _) = self.listener.accept()?;
+        stream.set_read_timeout(self.read_timeout)?;
+        stream.set_write_timeout(self.write_timeout)?;
+        Ok(UnixHttpStream(stream))
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(unspecified_addr())
+    }
+
+    fn set_read_timeout(&mut self, dur: Option<Duration>) {
+        self.read_timeout = dur;
+    }
+
+    fn set_write_timeout(&mut self, dur: Option<Duration>) {
+        self.write_timeout = dur;
+    }
+}
+
+/// Connection accepted on a Unix domain socket.
+pub struct UnixHttpStream(UnixStream);
+
+impl Clone for UnixHttpStream {
+    fn clone(&self) -> UnixHttpStream {
+        UnixHttpStream(self.0.try_clone().unwrap())
+    }
+}
+
+impl Read for UnixHttpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for UnixHttpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl NetworkStream for UnixHttpStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(unspecified_addr())
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        match self.0.shutdown(how) {
+            Ok(_) => Ok(()),
+            // The peer may already have gone away.
+            Err(ref e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+            Err(e) => Err(e)
+        }
+    }
+}
@@ -0,0 +1,501 @@
+//! Explicit waits, which poll a remote end until a condition holds.
+//!
+//! Conditions are checked by sending ordinary `WebDriverCommand`s, so they
+//! work with anything that implements [`CommandSender`], such as a
+//! [`Client`]:
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use webdriver::client::Client;
+//! # use webdriver::common::LocatorStrategy;
+//! # use webdriver::error::WebDriverResult;
+//! # use webdriver::wait::{self, Condition, Wait};
+//! # fn run(client: &Client) -> WebDriverResult<()> {
+//! let button = Wait::new(Duration::from_secs(10))
+//!     .until(client, wait::element_visible(LocatorStrategy::CSSSelector, "#submit"))?;
+//! client.click(&button)?;
+//! Wait::new(Duration::from_secs(10))
+//!     .until(client, wait::url_matches(|url| url.ends_with("/done"))
+//!            .and(wait::window_count(1)))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`CommandSender`]: trait.CommandSender.html
+//! [`Client`]: ../client/struct.Client.html
+
+use std::cmp;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustc_serialize::json::Json;
+
+use client::Client;
+use command::{LocatorParameters, WebDriverCommand, WebDriverExtensionCommand};
+use common::{LocatorStrategy, WebElement};
+use error::{ErrorStatus, WebDriverError, WebDriverResult};
+use httpapi::WebDriverExtensionRoute;
+use response::WebDriverResponse;
+
+/// Sends the commands used to check conditions.
+pub trait CommandSender<T: WebDriverExtensionCommand> {
+    fn send(&self, command: WebDriverCommand<T>) -> WebDriverResult<WebDriverResponse>;
+}
+
+impl<U: WebDriverExtensionRoute> CommandSender<U::Command> for Client<U> {
+    fn send(&self, command: WebDriverCommand<U::Command>) -> WebDriverResult<WebDriverResponse> {
+        Client::send(self, command)
+    }
+}
+
+/// Outcome of checking a condition once.
+#[derive(Debug, PartialEq)]
+pub enum Observed<T> {
+    /// The condition holds, giving this value.
+    Met(T),
+    /// The condition doesn't hold yet; describes what was observed instead.
+    NotMet(String),
+}
+
+/// Something to wait for.
+pub trait Condition {
+    type Output;
+
+    /// What the condition waits for, e.g. `element #submit to be visible`.
+    fn description(&self) -> String;
+
+    /// Check the condition once.
+    ///
+    /// Errors end the wait, except for `NoSuchElement` and
+    /// `StaleElementReference`, which are expected while a page changes and
+    /// count as the condition not being met.
+    fn check<T: WebDriverExtensionCommand>(&mut self, sender: &dyn CommandSender<T>)
+                                           -> WebDriverResult<Observed<Self::Output>>;
+
+    /// Wait for both this condition and `other`.
+    fn and<C: Condition>(self, other: C) -> And<Self, C> where Self: Sized {
+        And(self, other)
+    }
+
+    /// Wait for either this condition or `other`, whichever holds first.
+    fn or<C: Condition<Output=Self::Output>>(self, other: C) -> Or<Self, C> where Self: Sized {
+        Or(self, other)
+    }
+}
+
+/// Check `condition`, treating retryable errors as the condition not being
+/// met.
+fn observe<T, C>(condition: &mut C, sender: &dyn CommandSender<T>)
+                 -> WebDriverResult<Observed<C::Output>>
+    where T: WebDriverExtensionCommand,
+          C: Condition + ?Sized
+{
+    match condition.check(sender) {
+        Err(ref err) if err.error == ErrorStatus::NoSuchElement ||
+                        err.error == ErrorStatus::StaleElementReference => {
+            Ok(Observed::NotMet(format!("{}: {}", err.error_code(), err.message)))
+        },
+        result => result
+    }
+}
+
+/// Polls a condition until it holds or a timeout expires.
+#[derive(Clone, Copy, Debug)]
+pub struct Wait {
+    timeout: Duration,
+    interval: Duration,
+}
+
+impl Wait {
+    /// Wait for up to `timeout`, checking every 100 ms.
+    pub fn new(timeout: Duration) -> Wait {
+        Wait {
+            timeout: timeout,
+            interval: Duration::from_millis(100),
+        }
+    }
+
+    /// How long to wait between checks of the condition.
+    pub fn interval(mut self, interval: Duration) -> Wait {
+        self.interval = interval;
+        self
+    }
+
+    /// Check `condition` until it holds, returning its value.
+    ///
+    /// The condition is checked at least once, and once more when the
+    /// timeout expires. If it still doesn't hold then, the error is a
+    /// `Timeout` that describes the state observed by that last check.
+    pub fn until<T, S, C>(&self, sender: &S, mut condition: C) -> WebDriverResult<C::Output>
+        where T: WebDriverExtensionCommand,
+              S: CommandSender<T>,
+              C: Condition
+    {
+        let start = Instant::now();
+        loop {
+            let observed = match observe(&mut condition, sender)? {
+                Observed::Met(value) => return Ok(value),
+                Observed::NotMet(observed) => observed
+            };
+            let elapsed = start.elapsed();
+            if elapsed >= self.timeout {
+                return Err(WebDriverError::new(
+                    ErrorStatus::Timeout,
+                    format!("Timed out after {} ms waiting for {}; last observed {}",
+                            elapsed.as_millis(), condition.description(), observed)));
+            }
+            thread::sleep(cmp::min(self.interval, self.timeout - elapsed));
+        }
+    }
+}
+
+/// Condition that holds when both of two conditions hold.
+pub struct And<A, B>(A, B);
+
+impl<A: Condition, B: Condition> Condition for And<A, B> {
+    type Output = (A::Output, B::Output);
+
+    fn description(&self) -> String {
+        format!("{} and {}", self.0.description(), self.1.description())
+    }
+
+    fn check<T: WebDriverExtensionCommand>(&mut self, sender: &dyn CommandSender<T>)
+                                           -> WebDriverResult<Observed<Self::Output>> {
+        let first = match observe(&mut self.0, sender)? {
+            Observed::Met(value) => value,
+            Observed::NotMet(observed) => return Ok(Observed::NotMet(observed))
+        };
+        Ok(match observe(&mut self.1, sender)? {
+            Observed::Met(value) => Observed::Met((first, value)),
+            Observed::NotMet(observed) => Observed::NotMet(observed)
+        })
+    }
+}
+
+/// Condition that holds when either of two conditions holds.
+pub struct Or<A, B>(A, B);
+
+impl<A, B> Condition for Or<A, B>
+    where A: Condition,
+          B: Condition<Output=A::Output>
+{
+    type Output = A::Output;
+
+    fn description(&self) -> String {
+        format!("{} or {}", self.0.description(), self.1.description())
+    }
+
+    fn check<T: WebDriverExtensionCommand>(&mut self, sender: &dyn CommandSender<T>)
+                                           -> WebDriverResult<Observed<Self::Output>> {
+        let first = match observe(&mut self.0, sender)? {
+            Observed::Met(value) => return Ok(Observed::Met(value)),
+            Observed::NotMet(observed) => observed
+        };
+        Ok(match observe(&mut self.1, sender)? {
+            Observed::Met(value) => Observed::Met(value),
+            Observed::NotMet(observed) => Observed::NotMet(format!("{} and {}", first, observed))
+        })
+    }
+}
+
+fn value(response: WebDriverResponse) -> WebDriverResult<Json> {
+    match response {
+        WebDriverResponse::Generic(response) => Ok(response.value),
+        WebDriverResponse::Void => Ok(Json::Null),
+        _ => Err(WebDriverError::new(ErrorStatus::UnknownError, "Unexpected response"))
+    }
+}
+
+fn unexpected(what: &str, value: &Json) -> WebDriverError {
+    WebDriverError::new(ErrorStatus::UnknownError, format!("Unexpected {}: {}", what, value))
+}
+
+fn find<T: WebDriverExtensionCommand>(sender: &dyn CommandSender<T>, locator: &LocatorParameters)
+                                      -> WebDriverResult<WebElement> {
+    let element = value(sender.send(WebDriverCommand::FindElement(locator.clone()))?)?;
+    WebElement::from_json(&element).map_err(|_| unexpected("element", &element))
+}
+
+fn locator(using: LocatorStrategy, selector: &str) -> LocatorParameters {
+    LocatorParameters {
+        using: using,
+        value: selector.to_string(),
+    }
+}
+
+/// Condition that holds when an element is found and displayed.
+pub struct ElementVisible {
+    locator: LocatorParameters,
+}
+
+/// Wait for an element matching `selector` to be displayed, giving the
+/// element.
+pub fn element_visible(using: LocatorStrategy, selector: &str) -> ElementVisible {
+    ElementVisible { locator: locator(using, selector) }
+}
+
+impl Condition for ElementVisible {
+    type Output = WebElement;
+
+    fn description(&self) -> String {
+        format!("element {} to be visible", self.locator.value)
+    }
+
+    fn check<T: WebDriverExtensionCommand>(&mut self, sender: &dyn CommandSender<T>)
+                                           -> WebDriverResult<Observed<WebElement>> {
+        let element = find(sender, &self.locator)?;
+        match value(sender.send(WebDriverCommand::IsDisplayed(element.clone()))?)? {
+            Json::Boolean(true) => Ok(Observed::Met(element)),
+            Json::Boolean(false) => Ok(Observed::NotMet("element hidden".to_string())),
+            ref other => Err(unexpected("displayedness", other))
+        }
+    }
+}
+
+/// Condition that holds when the text of an element equals a value.
+pub struct TextEquals {
+    locator: LocatorParameters,
+    text: String,
+}
+
+/// Wait for the text of the element matching `selector` to be `text`,
+/// giving the element.
+pub fn text_equals(using: LocatorStrategy, selector: &str, text: &str) -> TextEquals {
+    TextEquals {
+        locator: locator(using, selector),
+        text: text.to_string(),
+    }
+}
+
+impl Condition for TextEquals {
+    type Output = WebElement;
+
+    fn description(&self) -> String {
+        format!("text of {} to be {:?}", self.locator.value, self.text)
+    }
+
+    fn check<T: WebDriverExtensionCommand>(&mut self, sender: &dyn CommandSender<T>)
+                                           -> WebDriverResult<Observed<WebElement>> {
+        let element = find(sender, &self.locator)?;
+        match value(sender.send(WebDriverCommand::GetElementText(element.clone()))?)? {
+            Json::String(ref text) if *text == self.text => Ok(Observed::Met(element)),
+            Json::String(text) => Ok(Observed::NotMet(format!("text {:?}", text))),
+            ref other => Err(unexpected("text", other))
+        }
+    }
+}
+
+/// Condition that holds when the current URL matches a predicate.
+pub struct UrlMatches<F> {
+    predicate: F,
+}
+
+/// Wait for the current URL to satisfy `predicate`, giving the URL.
+pub fn url_matches<F: FnMut(&str) -> bool>(predicate: F) -> UrlMatches<F> {
+    UrlMatches { predicate: predicate }
+}
+
+impl<F: FnMut(&str) -> bool> Condition for UrlMatches<F> {
+    type Output = String;
+
+    fn description(&self) -> String {
+        "URL to match".to_string()
+    }
+
+    fn check<T: WebDriverExtensionCommand>(&mut self, sender: &dyn CommandSender<T>)
+                                           -> WebDriverResult<Observed<String>> {
+        match value(sender.send(WebDriverCommand::GetCurrentUrl)?)? {
+            Json::String(url) => {
+                if (self.predicate)(&url) {
+                    Ok(Observed::Met(url))
+                } else {
+                    Ok(Observed::NotMet(format!("URL {}", url)))
+                }
+            },
+            ref other => Err(unexpected("URL", other))
+        }
+    }
+}
+
+/// Condition that holds when a number of windows are open.
+pub struct WindowCount {
+    count: usize,
+}
+
+/// Wait for exactly `count` windows to be open, giving their handles.
+pub fn window_count(count: usize) -> WindowCount {
+    WindowCount { count: count }
+}
+
+impl Condition for WindowCount {
+    type Output = Vec<String>;
+
+    fn description(&self) -> String {
+        format!("{} windows to be open", self.count)
+    }
+
+    fn check<T: WebDriverExtensionCommand>(&mut self, sender: &dyn CommandSender<T>)
+                                           -> WebDriverResult<Observed<Vec<String>>> {
+        let value = value(sender.send(WebDriverCommand::GetWindowHandles)?)?;
+        let handles = value.as_array()
+            .and_then(|handles| handles.iter()
+                      .map(|x| x.as_string().map(|x| x.to_string()))
+                      .collect::<Option<Vec<_>>>());
+        match handles {
+            Some(ref handles) if handles.len() == self.count => Ok(Observed::Met(handles.clone())),
+            Some(handles) => Ok(Observed::NotMet(format!("{} windows", handles.len()))),
+            None => Err(unexpected("window handles", &value))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    use rustc_serialize::json::{Json, ToJson};
+
+    use command::{VoidWebDriverExtensionCommand, WebDriverCommand};
+    use common::{LocatorStrategy, WebElement};
+    use error::{ErrorStatus, WebDriverError, WebDriverResult};
+    use response::{ValueResponse, WebDriverResponse};
+    use super::*;
+
+    /// Page whose button appears on the third poll, becomes visible on the
+    /// fourth and goes stale on the sixth.
+    struct FakePage {
+        polls: Cell<u32>,
+    }
+
+    impl FakePage {
+        fn new() -> FakePage {
+            FakePage { polls: Cell::new(0) }
+        }
+    }
+
+    impl CommandSender<VoidWebDriverExtensionCommand> for FakePage {
+        fn send(&self, command: WebDriverCommand<VoidWebDriverExtensionCommand>)
+                -> WebDriverResult<WebDriverResponse> {
+            let value = match command {
+                WebDriverCommand::GetCurrentUrl => {
+                    self.polls.set(self.polls.get() + 1);
+                    format!("http://example.com/{}", self.polls.get()).to_json()
+                },
+                WebDriverCommand::GetWindowHandles => {
+                    vec!["w1".to_string(), "w2".to_string()].to_json()
+                },
+                WebDriverCommand::FindElement(_) => {
+                    self.polls.set(self.polls.get() + 1);
+                    if self.polls.get() < 3 {
+                        return Err(WebDriverError::new(ErrorStatus::NoSuchElement,
+                                                       "Unable to locate #button"));
+                    }
+                    WebElement::new("button".to_string()).to_json()
+                },
+                WebDriverCommand::IsDisplayed(_) => (self.polls.get() >= 4).to_json(),
+                WebDriverCommand::GetElementText(_) => {
+                    if self.polls.get() >= 6 {
+                        return Err(WebDriverError::new(ErrorStatus::StaleElementReference,
+                                                       "Element is gone"));
+                    }
+                    "Submit".to_json()
+                },
+                _ => return Err(WebDriverError::new(ErrorStatus::UnsupportedOperation,
+                                                    "Unsupported"))
+            };
+            Ok(WebDriverResponse::Generic(ValueResponse { value: value }))
+        }
+    }
+
+    fn wait(timeout: u64) -> Wait {
+        Wait::new(Duration::from_millis(timeout)).interval(Duration::from_millis(1))
+    }
+
+    #[test]
+    fn test_element_visible() {
+        let page = FakePage::new();
+        let element = wait(1000).until(
+            &page, element_visible(LocatorStrategy::CSSSelector, "#button")).unwrap();
+        assert_eq!(element, WebElement::new("button".to_string()));
+        assert_eq!(page.polls.get(), 4);
+    }
+
+    #[test]
+    fn test_url_matches() {
+        let page = FakePage::new();
+        let url = wait(1000).until(&page, url_matches(|url| url.ends_with("/5"))).unwrap();
+        assert_eq!(url, "http://example.com/5");
+    }
+
+    #[test]
+    fn test_combinators() {
+        let page = FakePage::new();
+        let (url, handles) = wait(1000).until(
+            &page, url_matches(|url| url.ends_with("/2")).and(window_count(2))).unwrap();
+        assert_eq!(url, "http://example.com/2");
+        assert_eq!(handles, vec!["w1".to_string(), "w2".to_string()]);
+
+        let page = FakePage::new();
+        let element = wait(1000).until(
+            &page,
+            text_equals(LocatorStrategy::CSSSelector, "#button", "Cancel")
+                .or(element_visible(LocatorStrategy::CSSSelector, "#button"))).unwrap();
+        assert_eq!(element, WebElement::new("button".to_string()));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let page = FakePage::new();
+        let err = wait(20).until(&page, window_count(1)).unwrap_err();
+        assert_eq!(err.error, ErrorStatus::Timeout);
+        assert!(err.message.contains("waiting for 1 windows to be open; last observed 2 windows"),
+                "{}", err.message);
+
+        let page = FakePage::new();
+        let err = wait(20).until(
+            &page, text_equals(LocatorStrategy::CSSSelector, "#button", "Cancel")).unwrap_err();
+        assert_eq!(err.error, ErrorStatus::Timeout);
+        assert!(err.message.ends_with("last observed stale element reference: Element is gone"),
+                "{}", err.message);
+    }
+
+    #[test]
+    fn test_check_at_timeout() {
+        // Checked at 0 and 300 ms, then once more at the timeout rather than
+        // giving up because another interval doesn't fit
+        let page = FakePage::new();
+        let url = Wait::new(Duration::from_millis(500))
+            .interval(Duration::from_millis(300))
+            .until(&page, url_matches(|url| url.ends_with("/3")))
+            .unwrap();
+        assert_eq!(url, "http://example.com/3");
+        assert_eq!(page.polls.get(), 3);
+    }
+
+    #[test]
+    fn test_error_ends_wait() {
+        struct Broken;
+        impl CommandSender<VoidWebDriverExtensionCommand> for Broken {
+            fn send(&self, _: WebDriverCommand<VoidWebDriverExtensionCommand>)
+                    -> WebDriverResult<WebDriverResponse> {
+                Err(WebDriverError::new(ErrorStatus::NoSuchWindow, "Window closed"))
+            }
+        }
+        let err = wait(1000).until(&Broken, window_count(1)).unwrap_err();
+        assert_eq!(err.error, ErrorStatus::NoSuchWindow);
+
+        let handles = Json::from_str("{}").unwrap();
+        let err = wait(1000).until(&FixedValue(handles), window_count(1)).unwrap_err();
+        assert_eq!(err.error, ErrorStatus::UnknownError);
+    }
+
+    struct FixedValue(Json);
+
+    impl CommandSender<VoidWebDriverExtensionCommand> for FixedValue {
+        fn send(&self, _: WebDriverCommand<VoidWebDriverExtensionCommand>)
+                -> WebDriverResult<WebDriverResponse> {
+            Ok(WebDriverResponse::Generic(ValueResponse { value: self.0.clone() }))
+        }
+    }
+}